@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use eyre::{OptionExt, Result};
+use futures_util::future::join_all;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
+use url::Url;
+
+const PLACEHOLDER_NAME: &str = "placeholder.svg";
+
+/// How long artwork that could not be fetched is not asked for again.
+const RETRY_FAILED_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Shown when artwork can not be fetched, so players don't keep showing the previous track's art.
+const PLACEHOLDER_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 64 64">
+<rect width="64" height="64" fill="#41bdf5"/>
+<path d="M26 16v24.5a7 7 0 1 0 4 6.3V24h14v-8z" fill="#fff"/>
+</svg>
+"##;
+
+/// Limits for the downloaded artwork.
+#[derive(Debug, Clone)]
+pub struct ArtCacheSettings {
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+/// Downloads artwork with the bridge's credentials and serves it as `file://`
+/// URLs, since HA's `entity_picture` proxy needs a token most MPRIS clients
+/// don't have.
+///
+/// Files are named after a hash of their content, so the same cover shared by
+/// a whole album is stored once.
+pub struct ArtCache {
+    dir: PathBuf,
+    client: Client,
+    ha_url: Url,
+    /// Changes when a reloaded config has a new token.
+    ha_token: Mutex<String>,
+    settings: ArtCacheSettings,
+    /// Art URLs already downloaded in this run, pointing at their file.
+    downloaded: Mutex<HashMap<String, PathBuf>>,
+    /// Art URLs that could not be fetched, with when that happened.
+    failed: Mutex<HashMap<String, Instant>>,
+}
+
+impl ArtCache {
+    pub fn new(
+        client: Client,
+        ha_url: &str,
+        ha_token: String,
+        settings: ArtCacheSettings,
+    ) -> Result<Self> {
+        let dir = dirs::cache_dir()
+            .ok_or_eyre("Could not find cache directory")?
+            .join("ha_mpris_bridge/art");
+        Self::in_dir(dir, client, ha_url, ha_token, settings)
+    }
+
+    fn in_dir(
+        dir: PathBuf,
+        client: Client,
+        ha_url: &str,
+        ha_token: String,
+        settings: ArtCacheSettings,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(PLACEHOLDER_NAME), PLACEHOLDER_SVG)?;
+
+        Ok(Self {
+            dir,
+            client,
+            ha_url: Url::parse(ha_url)?,
+            ha_token: Mutex::new(ha_token),
+            settings,
+            downloaded: Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn set_token(&self, ha_token: String) {
+        *self.ha_token.lock().unwrap() = ha_token;
+    }
+
+    /// The local URL for `art_url` if that is known without fetching
+    /// anything: local files, artwork downloaded before, and the placeholder
+    /// for artwork that recently could not be fetched.
+    pub fn cached_url(&self, art_url: &str) -> Option<String> {
+        if art_url.is_empty() || art_url.starts_with("file://") {
+            return Some(art_url.to_string());
+        }
+        let downloaded = self.downloaded.lock().unwrap().get(art_url).cloned();
+        if let Some(path) = downloaded.filter(|path| path.exists()) {
+            return Some(file_url(&path));
+        }
+        let failed_at = self.failed.lock().unwrap().get(art_url).copied();
+        failed_at
+            .filter(|at| at.elapsed() < RETRY_FAILED_AFTER)
+            .map(|_| file_url(&self.dir.join(PLACEHOLDER_NAME)))
+    }
+
+    /// The cached copy of `art_url`, or no art while it is not cached yet, in
+    /// which case it is added to `missing` for [`ArtCache::fetch_in_background`].
+    pub fn cached_or_missing(&self, art_url: &str, missing: &mut Vec<String>) -> String {
+        self.cached_url(art_url).unwrap_or_else(|| {
+            missing.push(art_url.to_string());
+            String::new()
+        })
+    }
+
+    /// Downloads artwork without holding up the player asking for it, and
+    /// notifies `fetched` once all of it is cached or known to fail.
+    pub fn fetch_in_background(self: &Arc<Self>, art_urls: Vec<String>, fetched: Arc<Notify>) {
+        if art_urls.is_empty() {
+            return;
+        }
+        let cache = self.clone();
+        tokio::spawn(async move {
+            join_all(art_urls.iter().map(|url| cache.local_url(url))).await;
+            fetched.notify_one();
+        });
+    }
+
+    /// Rewrites an art URL to a local `file://` URL, falling back to the
+    /// placeholder image when the artwork can not be fetched.
+    pub async fn local_url(&self, art_url: &str) -> String {
+        if let Some(url) = self.cached_url(art_url) {
+            return url;
+        }
+        let path = match self.fetch(art_url).await {
+            Ok(path) => path,
+            Err(e) => {
+                println!("Could not fetch artwork {art_url}: {e}");
+                self.failed
+                    .lock()
+                    .unwrap()
+                    .insert(art_url.to_string(), Instant::now());
+                self.dir.join(PLACEHOLDER_NAME)
+            }
+        };
+        file_url(&path)
+    }
+
+    async fn fetch(&self, art_url: &str) -> Result<PathBuf> {
+        let url = Url::parse(art_url)?;
+        // Only HA itself gets to see the token, not third party artwork hosts
+        let mut request = self.client.get(url.clone());
+        if url.origin() == self.ha_url.origin() {
+            request = request.bearer_auth(self.ha_token.lock().unwrap().as_str());
+        }
+        let response = request.send().await?.error_for_status()?;
+        let extension = match response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some("image/png") => "png",
+            Some("image/gif") => "gif",
+            Some("image/webp") => "webp",
+            Some("image/svg+xml") => "svg",
+            _ => "jpg",
+        };
+        let bytes = response.bytes().await?;
+
+        let path = self
+            .dir
+            .join(format!("{:x}.{extension}", Sha256::digest(&bytes)));
+        if path.exists() {
+            // Keep artwork that is still in use from being expired first
+            std::fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(SystemTime::now())?;
+        } else {
+            tokio::fs::write(&path, &bytes).await?;
+            if let Err(e) = self.prune() {
+                println!("Could not clean up artwork cache: {e}");
+            }
+        }
+
+        self.downloaded
+            .lock()
+            .unwrap()
+            .insert(art_url.to_string(), path.clone());
+        Ok(path)
+    }
+
+    /// Removes expired files, then the least recently used ones until the
+    /// cache fits in its size limit.
+    fn prune(&self) -> Result<()> {
+        let now = SystemTime::now();
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_name() == PLACEHOLDER_NAME {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified()?;
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > self.settings.max_age {
+                std::fs::remove_file(entry.path())?;
+            } else {
+                files.push((modified, metadata.len(), entry.path()));
+            }
+        }
+
+        files.sort();
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in files {
+            if total <= self.settings.max_bytes {
+                break;
+            }
+            std::fs::remove_file(path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+fn file_url(path: &Path) -> String {
+    Url::from_file_path(path)
+        .map(|url| url.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\nnot really";
+
+    /// An art host answering every request with `status`, counting requests.
+    async fn art_host(status: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: image/png\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    PNG.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(PNG).await;
+            }
+        });
+        (format!("http://127.0.0.1:{port}"), requests)
+    }
+
+    fn cache(dir: &Path, ha_url: &str) -> Arc<ArtCache> {
+        let settings = ArtCacheSettings {
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(60),
+        };
+        Arc::new(
+            ArtCache::in_dir(
+                dir.to_path_buf(),
+                Client::new(),
+                ha_url,
+                "token".to_string(),
+                settings,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn artwork_is_fetched_in_the_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ha_url, requests) = art_host("200 OK").await;
+        let cache = cache(dir.path(), &ha_url);
+        let art_url = format!("{ha_url}/api/media_player_proxy/media_player.kitchen");
+
+        let mut missing = vec![];
+        assert_eq!(cache.cached_or_missing(&art_url, &mut missing), "");
+        assert_eq!(missing, vec![art_url.clone()]);
+        let fetched = Arc::new(Notify::new());
+        cache.fetch_in_background(missing, fetched.clone());
+        tokio::time::timeout(Duration::from_secs(5), fetched.notified())
+            .await
+            .unwrap();
+
+        let local = cache.cached_url(&art_url).unwrap();
+        assert!(local.starts_with("file://") && local.ends_with(".png"));
+        assert_eq!(cache.local_url(&art_url).await, local);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_artwork_is_not_asked_for_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ha_url, requests) = art_host("404 Not Found").await;
+        let cache = cache(dir.path(), &ha_url);
+        let art_url = format!("{ha_url}/missing.png");
+        let placeholder = file_url(&dir.path().join(PLACEHOLDER_NAME));
+
+        assert_eq!(cache.cached_url(&art_url), None);
+        assert_eq!(cache.local_url(&art_url).await, placeholder);
+        assert_eq!(cache.cached_url(&art_url), Some(placeholder.clone()));
+        assert_eq!(cache.local_url(&art_url).await, placeholder);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn local_and_missing_art_needs_no_fetching() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), "http://homeassistant.local:8123");
+
+        assert_eq!(cache.cached_url(""), Some(String::new()));
+        assert_eq!(
+            cache.cached_url("file:///tmp/cover.jpg").as_deref(),
+            Some("file:///tmp/cover.jpg")
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::{fmt::Display, future::Future};
+
+use serde_json::Value;
+
+use crate::media::MediaRequest;
+
+/// Stops browsing big libraries at a list a desktop menu can still show.
+const MAX_PLAYLISTS: usize = 100;
+
+/// One node of HA's `media_player/browse_media` tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrowseItem {
+    pub title: String,
+    pub media: MediaRequest,
+    /// Thumbnail URL, possibly relative to the HA instance.
+    pub thumbnail: Option<String>,
+    pub can_play: bool,
+    pub can_expand: bool,
+}
+
+impl BrowseItem {
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(Self {
+            title: value["title"].as_str()?.to_string(),
+            media: MediaRequest {
+                content_id: value["media_content_id"].as_str()?.to_string(),
+                content_type: value["media_content_type"].as_str()?.to_string(),
+            },
+            thumbnail: value["thumbnail"]
+                .as_str()
+                .filter(|t| !t.is_empty())
+                .map(str::to_string),
+            can_play: value["can_play"].as_bool().unwrap_or_default(),
+            can_expand: value["can_expand"].as_bool().unwrap_or_default(),
+        })
+    }
+
+    /// Local media from HA's media source, i.e. single files rather than
+    /// something worth listing as a playlist.
+    pub fn is_media_source(&self) -> bool {
+        self.media.content_id.starts_with("media-source://")
+    }
+}
+
+/// The children of a `browse_media` result.
+fn children(result: &Value) -> Vec<BrowseItem> {
+    result["children"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(BrowseItem::from_value)
+        .collect()
+}
+
+/// Collects what is worth publishing as playlists from the first two levels
+/// of the media browser: playable entries of the root, then those of its
+/// folders, leaving out local media. `browse` lists the children of a node,
+/// or of the root without one. Folders that can not be browsed are skipped.
+pub async fn playlists<F, E>(
+    entity_id: &str,
+    mut browse: impl FnMut(Option<MediaRequest>) -> F,
+) -> Result<Vec<BrowseItem>, E>
+where
+    F: Future<Output = Result<Value, E>>,
+    E: Display,
+{
+    let root = browse(None).await?;
+    let mut found = vec![];
+    let mut folders = vec![];
+    for item in children(&root) {
+        if item.is_media_source() {
+            continue;
+        }
+        if item.can_play {
+            found.push(item);
+        } else if item.can_expand {
+            folders.push(item);
+        }
+    }
+    for folder in folders {
+        if found.len() >= MAX_PLAYLISTS {
+            break;
+        }
+        match browse(Some(folder.media)).await {
+            Ok(result) => found.extend(children(&result).into_iter().filter(|item| item.can_play)),
+            Err(e) => println!("Could not browse {} of {entity_id}: {e}", folder.title),
+        }
+    }
+    found.truncate(MAX_PLAYLISTS);
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, future::ready};
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn node(title: &str, content_id: &str, can_play: bool, can_expand: bool) -> Value {
+        json!({
+            "title": title,
+            "media_content_id": content_id,
+            "media_content_type": "playlist",
+            "thumbnail": "/api/media_player_proxy/media_player.kitchen/browse_media/x",
+            "can_play": can_play,
+            "can_expand": can_expand,
+        })
+    }
+
+    fn listing(children: Vec<Value>) -> Value {
+        json!({"title": "Media", "children": children})
+    }
+
+    /// Browses a fixture tree: `root`, plus the children of folders by content id.
+    async fn browse_fixture(
+        root: Value,
+        folders: &[(&str, Value)],
+    ) -> (Result<Vec<BrowseItem>, String>, Vec<Option<String>>) {
+        let browsed = RefCell::new(vec![]);
+        let result = playlists("media_player.kitchen", |media: Option<MediaRequest>| {
+            let content_id = media.map(|m| m.content_id);
+            browsed.borrow_mut().push(content_id.clone());
+            ready(match content_id {
+                None => Ok(root.clone()),
+                Some(id) => folders
+                    .iter()
+                    .find(|(folder, _)| *folder == id)
+                    .map(|(_, children)| children.clone())
+                    .ok_or_else(|| format!("{id} not found")),
+            })
+        })
+        .await;
+        (result, browsed.into_inner())
+    }
+
+    fn titles(items: &[BrowseItem]) -> Vec<&str> {
+        items.iter().map(|item| item.title.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn playlists_come_from_the_root_and_its_folders() {
+        let root = listing(vec![
+            node("Radio 1", "radio://1", true, false),
+            node("Playlists", "library://playlists", false, true),
+            node("Broken", "library://broken", false, true),
+        ]);
+        let folders = [(
+            "library://playlists",
+            listing(vec![
+                node("Road trip", "library://playlist/1", true, true),
+                node("Artists", "library://artists", false, true),
+            ]),
+        )];
+        let (found, browsed) = browse_fixture(root, &folders).await;
+        let found = found.unwrap();
+
+        assert_eq!(titles(&found), ["Radio 1", "Road trip"]);
+        assert_eq!(
+            found[1].media,
+            MediaRequest {
+                content_id: "library://playlist/1".to_string(),
+                content_type: "playlist".to_string(),
+            }
+        );
+        assert_eq!(
+            found[1].thumbnail.as_deref(),
+            Some("/api/media_player_proxy/media_player.kitchen/browse_media/x")
+        );
+        // Only two levels deep, and a folder that fails is skipped
+        assert_eq!(
+            browsed,
+            [
+                None,
+                Some("library://playlists".to_string()),
+                Some("library://broken".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_media_and_incomplete_nodes_are_left_out() {
+        let root = listing(vec![
+            node("My media", "media-source://media_source", true, true),
+            json!({"title": "No content id", "media_content_type": "music", "can_play": true}),
+            node("Favorite", "favorite://1", true, false),
+        ]);
+        let (found, browsed) = browse_fixture(root, &[]).await;
+
+        assert_eq!(titles(&found.unwrap()), ["Favorite"]);
+        assert_eq!(browsed, [None]);
+    }
+
+    #[tokio::test]
+    async fn playlists_stop_at_the_limit() {
+        let root = listing(
+            (0..MAX_PLAYLISTS - 1)
+                .map(|i| {
+                    node(
+                        &format!("Station {i}"),
+                        &format!("radio://{i}"),
+                        true,
+                        false,
+                    )
+                })
+                .chain([
+                    node("Playlists", "library://playlists", false, true),
+                    node("Albums", "library://albums", false, true),
+                ])
+                .collect(),
+        );
+        let playlists = listing(
+            (0..5)
+                .map(|i| {
+                    node(
+                        &format!("Playlist {i}"),
+                        &format!("playlist://{i}"),
+                        true,
+                        false,
+                    )
+                })
+                .collect(),
+        );
+        let (found, browsed) = browse_fixture(root, &[("library://playlists", playlists)]).await;
+        let found = found.unwrap();
+
+        assert_eq!(found.len(), MAX_PLAYLISTS);
+        assert_eq!(found.last().unwrap().title, "Playlist 0");
+        // The limit is reached before the second folder
+        assert_eq!(browsed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failing_to_browse_the_root_is_an_error() {
+        let result = playlists("media_player.kitchen", |_| {
+            ready(Err::<Value, _>("not_supported".to_string()))
+        })
+        .await;
+        assert_eq!(result.unwrap_err(), "not_supported");
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+use serde_json::Value;
+
+use crate::{
+    error::{BridgeError, TOKEN_HELP},
+    get_config,
+    homeassistant::{fetch_media_players, websocket_url, MediaPlayer, TokenSource},
+    keyring, load_config, process_env, Config,
+};
+
+/// Bridges Home Assistant media players to MPRIS.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Config file to use instead of ~/.config/ha_mpris_bridge/config.toml.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Subcommand)]
+pub enum Command {
+    /// Bridge the configured media players (default).
+    #[default]
+    Run,
+    /// List every media player in Home Assistant, bridged or not.
+    ListPlayers,
+    /// Check the config, the connection to Home Assistant and the token.
+    Check,
+    /// Create or update the config file by answering a few questions.
+    Init,
+    /// Move the token to the Secret Service keyring.
+    StoreToken,
+}
+
+pub async fn list_players(config_file: &Path) -> Result<(), BridgeError> {
+    let config = load_config(config_file).await?;
+    let players = fetch(&config).await?;
+    print_players(&players);
+    Ok(())
+}
+
+pub async fn check(config_file: &Path) -> Result<(), BridgeError> {
+    let config = load_config(config_file).await?;
+    let selectors = config.selectors()?;
+    config
+        .tls_settings()
+        .http_client()
+        .map_err(BridgeError::config)?;
+    println!("Config file {} is valid", config_file.display());
+
+    drop(zbus::Connection::session().await?);
+    println!("D-Bus session bus is available");
+
+    let connection = config.connection_settings()?;
+    let (registry, players) = fetch_media_players(&connection).await?;
+    println!("Connected to {} and authenticated", connection.url);
+    registry.check_selectors(&selectors)?;
+
+    let matching: Vec<MediaPlayer> = players
+        .iter()
+        .filter(|p| selectors.iter().any(|s| s.matches(&p.entity_id, &registry)))
+        .cloned()
+        .collect();
+    println!(
+        "{} of {} media players match entity_ids",
+        matching.len(),
+        players.len()
+    );
+    print_players(&matching);
+    Ok(())
+}
+
+/// Asks for the HA URL, the token and the players to bridge, checks them
+/// against HA and writes the config file. Other settings of an existing
+/// config file are kept.
+pub async fn init(config_file: &Path) -> Result<(), BridgeError> {
+    let mut config = if config_file.exists() {
+        get_config(config_file).map_err(BridgeError::config)?
+    } else {
+        Config::default()
+    };
+    let placeholders = Config::default();
+
+    let players = loop {
+        let current_url = Some(config.home_assistant_url.as_str())
+            .filter(|url| *url != placeholders.home_assistant_url);
+        config.home_assistant_url = ask(
+            "Home Assistant URL",
+            Some(current_url.unwrap_or("http://homeassistant.local:8123")),
+        )?;
+        if let Err(e) = websocket_url(&config.home_assistant_url) {
+            println!("{e}");
+            continue;
+        }
+
+        if config.token_keyring && config.home_assistant_token.is_empty() {
+            if let Ok(token) = keyring::read_token(&config.home_assistant_url).await {
+                config.home_assistant_token = token;
+                config.token_source = TokenSource::Keyring;
+            }
+        }
+        println!("{TOKEN_HELP}");
+        let has_token = config.home_assistant_token != placeholders.home_assistant_token
+            && !config.home_assistant_token.is_empty();
+        let token = if has_token {
+            read_answer("Access token (empty keeps the current one): ")?
+        } else {
+            ask("Access token", None)?
+        };
+        if !token.is_empty() {
+            config.home_assistant_token = token;
+            config.token_source = TokenSource::ConfigFile;
+        }
+
+        match fetch(&config).await {
+            Ok(players) => break players,
+            Err(e @ (BridgeError::Auth { .. } | BridgeError::Transport(_))) => println!("{e}"),
+            Err(e) => return Err(e),
+        }
+    };
+
+    println!();
+    for (i, player) in players.iter().enumerate() {
+        match friendly_name(player) {
+            "" => println!("{:>3}. {}", i + 1, player.entity_id),
+            name => println!("{:>3}. {} ({name})", i + 1, player.entity_id),
+        }
+    }
+    let current = Some(config.entity_ids.join(" "))
+        .filter(|_| config.entity_ids != placeholders.entity_ids && !config.entity_ids.is_empty());
+    let answer = ask(
+        "Players to bridge: numbers from the list, entity ids, or \"all\"",
+        Some(current.as_deref().unwrap_or("all")),
+    )?;
+    config.entity_ids = answer
+        .split([' ', ','])
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.parse::<usize>() {
+            Ok(n) if (1..=players.len()).contains(&n) => players[n - 1].entity_id.clone(),
+            _ => s.to_string(),
+        })
+        .collect();
+    config.selectors()?;
+
+    config.token_keyring = false;
+    if ask_yes_no("Store the token in the Secret Service keyring instead of the config file")? {
+        let (url, token) = (
+            config.home_assistant_url.clone(),
+            config.home_assistant_token.clone(),
+        );
+        if let Err(e) = move_token_to_keyring(&mut config, &url, &token).await {
+            println!("{e}");
+            println!("Keeping the token in the config file");
+        }
+    }
+
+    config.save(config_file).map_err(BridgeError::config)?;
+    println!("Wrote {}", config_file.display());
+    Ok(())
+}
+
+/// Asks for a token, checks it with HA and stores it in the Secret Service
+/// keyring. The config file then only says to look there.
+pub async fn store_token(config_file: &Path) -> Result<(), BridgeError> {
+    let mut config = get_config(config_file).map_err(BridgeError::config)?;
+    // Only the plaintext token (or `HA_TOKEN`) is worth moving to the keyring
+    let mut target = Config {
+        home_assistant_token_file: None,
+        token_command: None,
+        token_keyring: false,
+        ..config.clone()
+    };
+    target.resolve_url(&process_env)?;
+    let has_token = target.resolve_token(&process_env).await.is_ok();
+
+    println!("{TOKEN_HELP}");
+    let token = if has_token {
+        read_answer("Access token (empty moves the one from the config file): ")?
+    } else {
+        ask("Access token", None)?
+    };
+    if !token.is_empty() {
+        target.home_assistant_token = token;
+        target.token_source = TokenSource::ConfigFile;
+    }
+    fetch_media_players(&target.connection_settings()?).await?;
+
+    move_token_to_keyring(
+        &mut config,
+        &target.home_assistant_url,
+        &target.home_assistant_token,
+    )
+    .await?;
+    config.save(config_file).map_err(BridgeError::config)?;
+    println!(
+        "Stored the token for {} in the keyring and updated {}",
+        target.home_assistant_url,
+        config_file.display()
+    );
+    Ok(())
+}
+
+async fn move_token_to_keyring(
+    config: &mut Config,
+    ha_url: &str,
+    token: &str,
+) -> Result<(), BridgeError> {
+    keyring::store_token(ha_url, token).await?;
+    config.token_keyring = true;
+    config.home_assistant_token = String::new();
+    Ok(())
+}
+
+async fn fetch(config: &Config) -> Result<Vec<MediaPlayer>, BridgeError> {
+    let (_, mut players) = fetch_media_players(&config.connection_settings()?).await?;
+    players.sort_by(|a, b| a.entity_id.cmp(&b.entity_id));
+    Ok(players)
+}
+
+fn friendly_name(player: &MediaPlayer) -> &str {
+    player
+        .attributes
+        .get("friendly_name")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+fn print_players(players: &[MediaPlayer]) {
+    let width = players
+        .iter()
+        .map(|p| p.entity_id.len())
+        .max()
+        .unwrap_or_default();
+    let name_width = players
+        .iter()
+        .map(|p| friendly_name(p).len())
+        .max()
+        .unwrap_or_default();
+    for player in players {
+        println!(
+            "{:width$}  {:name_width$}  {}",
+            player.entity_id,
+            friendly_name(player),
+            player.state
+        );
+    }
+}
+
+fn ask_yes_no(question: &str) -> Result<bool, BridgeError> {
+    loop {
+        match read_answer(&format!("{question}? [Y/n]: "))?
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => continue,
+        }
+    }
+}
+
+/// Asks until there is an answer, falling back to `default` for an empty one.
+fn ask(question: &str, default: Option<&str>) -> Result<String, BridgeError> {
+    loop {
+        let answer = match default {
+            Some(default) => read_answer(&format!("{question} [{default}]: "))?,
+            None => read_answer(&format!("{question}: "))?,
+        };
+        match (answer.is_empty(), default) {
+            (false, _) => return Ok(answer),
+            (true, Some(default)) => return Ok(default.to_string()),
+            (true, None) => continue,
+        }
+    }
+}
+
+fn read_answer(prompt: &str) -> Result<String, BridgeError> {
+    let read = |e: std::io::Error| BridgeError::Config(format!("Could not read the answer: {e}"));
+    print!("{prompt}");
+    std::io::stdout().flush().map_err(read)?;
+    let mut answer = String::new();
+    if std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(read)?
+        == 0
+    {
+        return Err(BridgeError::Config("No answer given".to_string()));
+    }
+    Ok(answer.trim().to_string())
+}
@@ -0,0 +1,518 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use eyre::Result;
+use serde_json::Value;
+use tokio::sync::mpsc::{self, Sender};
+use wildmatch::WildMatch;
+
+use crate::{
+    art::ArtCache,
+    error::BridgeError,
+    homeassistant::{HAConnection, HAEvent, HAState, MediaPlayer, MediaPlayerState},
+    media::OpenUriSettings,
+    mpris::{new_mpris_player, RaiseAction},
+    queue::QueueSource,
+};
+
+/// One entry of `entity_ids` in the config.
+///
+/// Besides plain entity ids this accepts glob patterns (`media_player.living_*`),
+/// `area:<area id or name>`, `label:<label id or name>` and `all`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntitySelector {
+    All,
+    Entity(String),
+    Glob(String),
+    Area(String),
+    Label(String),
+}
+
+impl EntitySelector {
+    pub fn parse(selector: &str) -> Result<Self> {
+        let selector = selector.trim();
+        Ok(if selector == "all" {
+            Self::All
+        } else if let Some(area) = selector.strip_prefix("area:") {
+            eyre::ensure!(!area.is_empty(), "Area selector is missing the area");
+            Self::Area(area.to_string())
+        } else if let Some(label) = selector.strip_prefix("label:") {
+            eyre::ensure!(!label.is_empty(), "Label selector is missing the label");
+            Self::Label(label.to_string())
+        } else if selector.contains(['*', '?']) {
+            Self::Glob(selector.to_string())
+        } else {
+            Self::Entity(selector.to_string())
+        })
+    }
+
+    /// Registries the selector looks entities up in.
+    fn registries(&self) -> &'static [RegistryKind] {
+        match self {
+            Self::Area(_) => &[
+                RegistryKind::Entity,
+                RegistryKind::Device,
+                RegistryKind::Area,
+            ],
+            Self::Label(_) => &[
+                RegistryKind::Entity,
+                RegistryKind::Device,
+                RegistryKind::Label,
+            ],
+            _ => &[],
+        }
+    }
+
+    pub fn matches(&self, entity_id: &str, registry: &Registry) -> bool {
+        match self {
+            Self::All => true,
+            Self::Entity(id) => id == entity_id,
+            Self::Glob(pattern) => WildMatch::new(pattern).matches(entity_id),
+            Self::Area(area) => registry
+                .area_of(entity_id)
+                .is_some_and(|id| registry.areas.is(id, area)),
+            Self::Label(label) => registry
+                .labels_of(entity_id)
+                .any(|id| registry.labels.is(id, label)),
+        }
+    }
+}
+
+/// The HA registries that can be followed for changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryKind {
+    Entity,
+    Device,
+    Area,
+    Label,
+}
+
+impl RegistryKind {
+    pub const ALL: [Self; 4] = [Self::Entity, Self::Device, Self::Area, Self::Label];
+
+    /// WebSocket command listing the registry.
+    pub fn list_command(&self) -> &'static str {
+        match self {
+            Self::Entity => "config/entity_registry/list",
+            Self::Device => "config/device_registry/list",
+            Self::Area => "config/area_registry/list",
+            Self::Label => "config/label_registry/list",
+        }
+    }
+
+    /// Event fired whenever the registry changes.
+    pub fn updated_event(&self) -> &'static str {
+        match self {
+            Self::Entity => "entity_registry_updated",
+            Self::Device => "device_registry_updated",
+            Self::Area => "area_registry_updated",
+            Self::Label => "label_registry_updated",
+        }
+    }
+
+    pub fn from_event(event_type: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.updated_event() == event_type)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct RegistryEntry {
+    /// Name given by the user in HA, or else by the integration.
+    name: Option<String>,
+    /// The integration providing the entity, e.g. `sonos`.
+    platform: Option<String>,
+    area_id: Option<String>,
+    device_id: Option<String>,
+    labels: Vec<String>,
+}
+
+/// Ids mapped to their human readable names, so selectors can use either.
+#[derive(Debug, Clone, Default)]
+struct Names(HashMap<String, String>);
+
+impl Names {
+    fn is(&self, id: &str, wanted: &str) -> bool {
+        id == wanted
+            || self
+                .0
+                .get(id)
+                .is_some_and(|name| name.eq_ignore_ascii_case(wanted))
+    }
+}
+
+/// The parts of the HA registries needed to resolve area and label selectors.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    entities: HashMap<String, RegistryEntry>,
+    devices: HashMap<String, RegistryEntry>,
+    areas: Names,
+    labels: Names,
+    /// Registries HA could not list, e.g. labels before HA 2024.4.
+    missing: Vec<RegistryKind>,
+}
+
+impl Registry {
+    /// Records that HA could not list a registry, leaving it empty.
+    pub fn mark_missing(&mut self, kind: RegistryKind) {
+        self.update(kind, &Value::Null);
+        self.missing.push(kind);
+    }
+
+    /// Fails when an area or label selector needs a registry HA could not list.
+    pub fn check_selectors(&self, selectors: &[EntitySelector]) -> Result<(), BridgeError> {
+        for selector in selectors {
+            let Some(kind) = selector
+                .registries()
+                .iter()
+                .find(|kind| self.missing.contains(kind))
+            else {
+                continue;
+            };
+            let selector = match selector {
+                EntitySelector::Area(area) => format!("area:{area}"),
+                EntitySelector::Label(label) => format!("label:{label}"),
+                _ => continue,
+            };
+            return Err(BridgeError::Config(format!(
+                "entity_ids has \"{selector}\", but Home Assistant does not support {}. \
+                 Update Home Assistant, or list the players by entity id instead",
+                kind.list_command()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Replaces one registry with the result of its list command.
+    pub fn update(&mut self, kind: RegistryKind, list: &Value) {
+        let items = list.as_array().map(Vec::as_slice).unwrap_or_default();
+        let entry = |item: &Value| RegistryEntry {
+            name: item["name_by_user"]
+                .as_str()
+                .or(item["name"].as_str())
+                .map(str::to_string),
+            platform: item["platform"].as_str().map(str::to_string),
+            area_id: item["area_id"].as_str().map(str::to_string),
+            device_id: item["device_id"].as_str().map(str::to_string),
+            labels: item["labels"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|l| l.as_str().map(str::to_string))
+                .collect(),
+        };
+        let names = |id_key: &str| {
+            Names(
+                items
+                    .iter()
+                    .filter_map(|item| {
+                        Some((
+                            item[id_key].as_str()?.to_string(),
+                            item["name"].as_str()?.to_string(),
+                        ))
+                    })
+                    .collect(),
+            )
+        };
+        self.missing.retain(|missing| *missing != kind);
+        match kind {
+            RegistryKind::Entity => {
+                self.entities = items
+                    .iter()
+                    .filter_map(|item| Some((item["entity_id"].as_str()?.to_string(), entry(item))))
+                    .collect()
+            }
+            RegistryKind::Device => {
+                self.devices = items
+                    .iter()
+                    .filter_map(|item| Some((item["id"].as_str()?.to_string(), entry(item))))
+                    .collect()
+            }
+            RegistryKind::Area => self.areas = names("area_id"),
+            RegistryKind::Label => self.labels = names("label_id"),
+        }
+    }
+
+    fn platform_of(&self, entity_id: &str) -> Option<&str> {
+        self.entities.get(entity_id)?.platform.as_deref()
+    }
+
+    fn device_name_of(&self, entity_id: &str) -> Option<&str> {
+        self.device_of(entity_id)?.name.as_deref()
+    }
+
+    fn device_of(&self, entity_id: &str) -> Option<&RegistryEntry> {
+        self.entities
+            .get(entity_id)
+            .and_then(|e| e.device_id.as_ref())
+            .and_then(|d| self.devices.get(d))
+    }
+
+    /// Entities without an area of their own inherit the area of their device.
+    fn area_of(&self, entity_id: &str) -> Option<&str> {
+        self.entities
+            .get(entity_id)
+            .and_then(|e| e.area_id.as_deref())
+            .or_else(|| self.device_of(entity_id)?.area_id.as_deref())
+    }
+
+    fn labels_of<'a>(&'a self, entity_id: &str) -> impl Iterator<Item = &'a String> {
+        let own = self.entities.get(entity_id).map(|e| e.labels.iter());
+        let device = self.device_of(entity_id).map(|d| d.labels.iter());
+        own.into_iter()
+            .flatten()
+            .chain(device.into_iter().flatten())
+    }
+}
+
+/// Options for a single bridged player, resolved from the config and the
+/// entity registry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlayerSettings {
+    /// HA states that keep the player on the bus, e.g. to hide it while the
+    /// entity is off or unavailable. Without a list it is visible in any state.
+    pub visible_states: Option<Vec<HAState>>,
+    pub open_uri: OpenUriSettings,
+    /// Configured `Identity`, taking precedence over HA's names.
+    pub name: Option<String>,
+    /// Name of the device the entity belongs to, for entities without a `friendly_name`.
+    pub device_name: Option<String>,
+    pub raise: RaiseAction,
+    /// Whether `Quit` turns the entity off.
+    pub quit_turns_off: bool,
+    /// Volume and seek commands within this window are merged into one.
+    pub coalesce_window: Duration,
+    /// Where the play queue for the TrackList interface comes from.
+    pub queue: Option<QueueSource>,
+}
+
+impl PlayerSettings {
+    fn is_visible(&self, state: HAState) -> bool {
+        match &self.visible_states {
+            Some(states) => states.contains(&state),
+            None => true,
+        }
+    }
+}
+
+/// The global player settings, plus overrides for single entities.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsByEntity {
+    pub default: PlayerSettings,
+    pub per_entity: HashMap<String, PlayerSettings>,
+}
+
+impl SettingsByEntity {
+    pub fn get(&self, entity_id: &str) -> &PlayerSettings {
+        self.per_entity.get(entity_id).unwrap_or(&self.default)
+    }
+}
+
+pub struct BridgedPlayer {
+    pub state: MediaPlayerState,
+    pub channel: Sender<HAEvent>,
+}
+
+/// Keeps one MPRIS server per HA media player matching the configured
+/// selectors, creating and dropping them as entities come and go.
+pub struct Players {
+    selectors: Vec<EntitySelector>,
+    settings: SettingsByEntity,
+    base_url: String,
+    art_cache: Arc<ArtCache>,
+    connection: HAConnection,
+    registry: Registry,
+    /// Last known state of every media player in HA, bridged or not.
+    known: HashMap<String, MediaPlayer>,
+    bridged: HashMap<String, BridgedPlayer>,
+}
+
+impl Players {
+    pub fn new(
+        selectors: Vec<EntitySelector>,
+        settings: SettingsByEntity,
+        base_url: String,
+        art_cache: Arc<ArtCache>,
+        connection: HAConnection,
+    ) -> Self {
+        Self {
+            selectors,
+            settings,
+            base_url,
+            art_cache,
+            connection,
+            registry: Registry::default(),
+            known: HashMap::new(),
+            bridged: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, entity_id: &str) -> Option<&BridgedPlayer> {
+        self.bridged.get(entity_id)
+    }
+
+    /// Applies the selectors and settings of a reloaded config. Players whose
+    /// settings changed are recreated, the others keep running.
+    pub fn reconfigure(&mut self, selectors: Vec<EntitySelector>, settings: SettingsByEntity) {
+        let previous = std::mem::replace(&mut self.settings, settings);
+        self.selectors = selectors;
+        self.bridged.retain(|entity_id, _| {
+            let keep = previous.get(entity_id) == self.settings.get(entity_id);
+            if !keep {
+                println!("Restarting MPRIS player for {entity_id} with new settings");
+            }
+            keep
+        });
+        self.sync();
+    }
+
+    /// Switches to another HA instance, or another way of reaching it. All
+    /// players are dropped, the next connection brings back the ones it has.
+    pub fn replace_server(&mut self, base_url: String, art_cache: Arc<ArtCache>) {
+        for entity_id in self.bridged.keys() {
+            println!("Removing MPRIS player for {entity_id}");
+        }
+        self.bridged.clear();
+        self.known.clear();
+        self.registry = Registry::default();
+        self.base_url = base_url;
+        self.art_cache = art_cache;
+    }
+
+    /// Fails when an area or label selector needs a registry HA could not list.
+    pub fn check_registry(&self) -> Result<(), BridgeError> {
+        self.registry.check_selectors(&self.selectors)
+    }
+
+    pub fn update_registry(&mut self, kind: RegistryKind, list: &Value) {
+        self.registry.update(kind, list);
+        self.sync();
+    }
+
+    /// Replaces the registry and every known state, e.g. after (re)connecting.
+    pub fn reset(&mut self, registry: Registry, states: Vec<MediaPlayer>) {
+        self.registry = registry;
+        self.known = states
+            .into_iter()
+            .filter(|m| m.entity_id.starts_with("media_player."))
+            .map(|m| (m.entity_id.clone(), m))
+            .collect();
+        self.sync();
+    }
+
+    /// Marks every player unavailable while HA can not be reached. Only the
+    /// name is kept, so clients show the players without any controls.
+    pub async fn mark_unavailable(&mut self) {
+        for player in self.known.values_mut() {
+            player.state = "unavailable".to_string();
+            player.attributes.retain(|key, _| key == "friendly_name");
+        }
+        self.sync();
+        self.publish_all().await;
+    }
+
+    /// Sends the last known state of `entity_id` to its MPRIS server.
+    pub async fn publish(&self, entity_id: &str) {
+        let (Some(player), Some(state)) = (self.bridged.get(entity_id), self.known.get(entity_id))
+        else {
+            return;
+        };
+        match player.state.update_metadata(state) {
+            Ok(event) => {
+                // A closed channel means the MPRIS server died. The next
+                // `sync` starts it again, with the latest state.
+                let _ = player.channel.send(event).await;
+            }
+            Err(e) => println!("Died during metadata update event with {e}"),
+        }
+    }
+
+    pub async fn publish_all(&self) {
+        for entity_id in self.bridged.keys() {
+            self.publish(entity_id).await;
+        }
+    }
+
+    /// Records a `state_changed` event. `None` means the entity was removed.
+    pub fn set_state(&mut self, entity_id: &str, state: Option<MediaPlayer>) {
+        match state {
+            Some(state) => self.known.insert(entity_id.to_string(), state),
+            None => self.known.remove(entity_id),
+        };
+        // Both new entities and state changes (e.g. turning off) can affect what is bridged
+        self.sync();
+    }
+
+    fn is_wanted(&self, player: &MediaPlayer) -> bool {
+        self.selectors
+            .iter()
+            .any(|s| s.matches(&player.entity_id, &self.registry))
+            && self
+                .settings
+                .get(&player.entity_id)
+                .is_visible(HAState::from(player.state.as_str()))
+    }
+
+    /// Starts MPRIS servers for newly matching players and stops the ones
+    /// that no longer match, are hidden in their current state or
+    /// disappeared from HA. Servers that died are started again.
+    fn sync(&mut self) {
+        let wanted: Vec<String> = self
+            .known
+            .values()
+            .filter(|player| self.is_wanted(player))
+            .map(|player| player.entity_id.clone())
+            .collect();
+
+        self.bridged.retain(|entity_id, player| {
+            if !wanted.contains(entity_id) {
+                println!("Removing MPRIS player for {entity_id}");
+                return false;
+            }
+            // The MPRIS server stopped with an error, which it logged. Dropping
+            // it here starts a new one below.
+            if player.channel.is_closed() {
+                println!("Restarting MPRIS player for {entity_id}");
+                return false;
+            }
+            true
+        });
+
+        for entity_id in wanted {
+            if self.bridged.contains_key(&entity_id) {
+                continue;
+            }
+            println!("Adding MPRIS player for {entity_id}");
+            let (ha_tx, ha_rx) = mpsc::channel(100);
+            let mut settings = self.settings.get(&entity_id).clone();
+            settings.queue = self
+                .registry
+                .platform_of(&entity_id)
+                .and_then(QueueSource::for_platform);
+            settings.device_name = self.registry.device_name_of(&entity_id).map(str::to_string);
+            let task = new_mpris_player(
+                entity_id.clone(),
+                self.known[&entity_id].clone(),
+                self.base_url.clone(),
+                self.art_cache.clone(),
+                settings,
+                ha_rx,
+                self.connection.clone(),
+            );
+            let task_entity_id = entity_id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = task.await {
+                    eprintln!("MPRIS player for {task_entity_id} stopped: {e}");
+                }
+            });
+            self.bridged.insert(
+                entity_id.clone(),
+                BridgedPlayer {
+                    state: MediaPlayerState::new(entity_id, self.base_url.clone()),
+                    channel: ha_tx,
+                },
+            );
+        }
+    }
+}
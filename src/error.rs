@@ -0,0 +1,154 @@
+use std::{fmt, process::ExitCode};
+
+use tokio_tungstenite::tungstenite;
+
+use crate::homeassistant::TokenSource;
+
+/// How to get a token HA accepts, shown whenever the configured one is not.
+pub const TOKEN_HELP: &str = "Create a long-lived access token in Home Assistant \
+     on your profile page, under Security > Long-lived access tokens. The token \
+     is only shown once, right after creating it.";
+
+/// Everything that can stop the bridge, grouped by what has to be fixed.
+#[derive(Debug)]
+pub enum BridgeError {
+    /// The config file can not be read or has invalid values.
+    Config(String),
+    /// Home Assistant rejected the access token taken from `source`.
+    Auth {
+        message: String,
+        source: TokenSource,
+    },
+    /// Home Assistant can not be reached or the connection dropped.
+    Transport(String),
+    /// Home Assistant sent something the bridge does not understand.
+    Protocol(String),
+    /// The D-Bus session bus can not be used.
+    DBus(String),
+}
+
+impl BridgeError {
+    pub fn config(error: impl fmt::Display) -> Self {
+        Self::Config(format!("{error:#}"))
+    }
+
+    /// Whether reconnecting may fix it without the user stepping in.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Self::Transport(_) | Self::Protocol(_))
+    }
+
+    /// Exit codes follow sysexits.h, so service managers can tell a broken
+    /// config from an unreachable server.
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Self::Config(_) => 78,
+            Self::Auth { .. } => 77,
+            Self::Transport(_) => 69,
+            Self::Protocol(_) => 76,
+            Self::DBus(_) => 71,
+        })
+    }
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(message) => write!(f, "Invalid configuration: {message}"),
+            Self::Auth { message, source } => write!(
+                f,
+                "Home Assistant rejected {source} ({message}). {TOKEN_HELP} {}",
+                source.fix()
+            ),
+            Self::Transport(message) => write!(
+                f,
+                "Could not talk to Home Assistant: {message}. \
+                 Check that home_assistant_url is reachable from this machine"
+            ),
+            Self::Protocol(message) => {
+                write!(f, "Unexpected response from Home Assistant: {message}")
+            }
+            Self::DBus(message) => write!(f, "D-Bus error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for BridgeError {}
+
+impl From<tungstenite::Error> for BridgeError {
+    fn from(error: tungstenite::Error) -> Self {
+        Self::Transport(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for BridgeError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Protocol(error.to_string())
+    }
+}
+
+impl From<zbus::Error> for BridgeError {
+    fn from(error: zbus::Error) -> Self {
+        Self::DBus(format!(
+            "{error}. The bridge has to run inside a desktop session with a session bus"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> BridgeError {
+        BridgeError::Auth {
+            message: String::new(),
+            source: TokenSource::ConfigFile,
+        }
+    }
+
+    #[test]
+    fn only_connection_problems_are_recoverable() {
+        assert!(BridgeError::Transport(String::new()).is_recoverable());
+        assert!(BridgeError::Protocol(String::new()).is_recoverable());
+        assert!(!BridgeError::Config(String::new()).is_recoverable());
+        assert!(!auth().is_recoverable());
+        assert!(!BridgeError::DBus(String::new()).is_recoverable());
+    }
+
+    #[test]
+    fn exit_codes_follow_sysexits() {
+        let code = |error: BridgeError| error.exit_code();
+        assert_eq!(code(BridgeError::Config(String::new())), ExitCode::from(78));
+        assert_eq!(code(auth()), ExitCode::from(77));
+        assert_eq!(
+            code(BridgeError::Transport(String::new())),
+            ExitCode::from(69)
+        );
+        assert_eq!(
+            code(BridgeError::Protocol(String::new())),
+            ExitCode::from(76)
+        );
+        assert_eq!(code(BridgeError::DBus(String::new())), ExitCode::from(71));
+    }
+
+    #[test]
+    fn auth_errors_name_the_token_source() {
+        let error = BridgeError::Auth {
+            message: "Invalid access token or password".to_string(),
+            source: TokenSource::File("/run/credentials/ha_token".into()),
+        };
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Home Assistant rejected the token from home_assistant_token_file \
+                 /run/credentials/ha_token (Invalid access token or password). \
+                 {TOKEN_HELP} Write it to /run/credentials/ha_token."
+            )
+        );
+    }
+
+    #[test]
+    fn websocket_errors_are_transport_errors() {
+        let error = BridgeError::from(tungstenite::Error::ConnectionClosed);
+        assert!(matches!(error, BridgeError::Transport(_)));
+    }
+}
@@ -1,17 +1,39 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
+use chrono::{DateTime, Utc};
 use eyre::{OptionExt, Result};
 use futures_util::{SinkExt, StreamExt};
-use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Error, Value};
-use tokio::sync::{
-    mpsc::{Receiver, Sender},
-    Mutex,
+use tokio::{
+    net::TcpStream,
+    sync::{
+        mpsc::{Receiver, Sender},
+        oneshot, watch,
+    },
+    time::Instant,
+};
+use tokio_tungstenite::{
+    connect_async_tls_with_config, tungstenite::protocol::Message, Connector, MaybeTlsStream,
+    WebSocketStream,
 };
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use url::Url;
 
+use crate::{
+    discovery::{EntitySelector, Players, Registry, RegistryKind, SettingsByEntity},
+    error::BridgeError,
+    media::MediaRequest,
+    queue::{Enqueue, QueueSource},
+};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How long HTTP requests, e.g. for artwork, may take to connect.
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long HTTP requests may take in total.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct MediaPlayer {
     pub entity_id: String,
@@ -19,98 +41,521 @@ pub struct MediaPlayer {
     pub state: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct MediaPlayerState {
     ha_url: String,
-    ha_token: String,
     pub entity_id: String,
 }
 
+/// Failure reported for a service call, either by HA itself or by the bridge
+/// when the command never made it to HA.
+#[derive(Debug, Clone)]
+pub struct ServiceCallError {
+    /// HA error code, e.g. `not_found` or `home_assistant_error`.
+    pub code: String,
+    pub message: String,
+}
+
+impl ServiceCallError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+
+    fn from_result(result: &Value) -> Self {
+        Self::new(
+            result["error"]["code"].as_str().unwrap_or("unknown_error"),
+            result["error"]["message"]
+                .as_str()
+                .unwrap_or("Home Assistant did not give a reason"),
+        )
+    }
+}
+
+impl std::fmt::Display for ServiceCallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ServiceCallError {}
+
+/// Where the outcome of a service call is reported back to the MPRIS side.
+/// Services called with `return_response` hand their response back through it.
+pub type CommandReply = oneshot::Sender<std::result::Result<Value, ServiceCallError>>;
+
+/// How the WebSocket connection is checked while nothing else is going on.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    /// Time between pings.
+    pub interval: Duration,
+    /// How long HA gets to answer a ping before the connection counts as dead.
+    pub timeout: Duration,
+}
+
+/// Where the access token came from, so a rejected token can be fixed where
+/// it is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TokenSource {
+    /// `home_assistant_token` in the config file.
+    #[default]
+    ConfigFile,
+    /// The `HA_TOKEN` environment variable.
+    Environment,
+    /// The output of `token_command`.
+    Command(String),
+    /// `home_assistant_token_file`.
+    File(PathBuf),
+    /// The Secret Service keyring.
+    Keyring,
+}
+
+impl TokenSource {
+    /// Where to put a new token.
+    pub fn fix(&self) -> String {
+        match self {
+            Self::ConfigFile => "Paste it as home_assistant_token in the config file.".to_string(),
+            Self::Environment => "Set it as the HA_TOKEN environment variable.".to_string(),
+            Self::Command(command) => format!("Make token_command `{command}` print it."),
+            Self::File(path) => format!("Write it to {}.", path.display()),
+            Self::Keyring => {
+                "Store it in the keyring with `homeassistant-mpris-bridge-rust store-token`."
+                    .to_string()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConfigFile => write!(f, "home_assistant_token from the config file"),
+            Self::Environment => write!(f, "the token from the HA_TOKEN environment variable"),
+            Self::Command(command) => write!(f, "the token printed by token_command `{command}`"),
+            Self::File(path) => write!(
+                f,
+                "the token from home_assistant_token_file {}",
+                path.display()
+            ),
+            Self::Keyring => write!(f, "the token from the Secret Service keyring"),
+        }
+    }
+}
+
+/// Everything needed to connect to HA.
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    /// The WebSocket API endpoint, see `websocket_url`.
+    pub url: Url,
+    pub connector: Connector,
+    pub access_token: String,
+    pub token_source: TokenSource,
+    pub heartbeat: Heartbeat,
+}
+
+/// Delay between reconnect attempts. It doubles from `initial` up to `max`,
+/// and half of it is random so bridges restarted together do not retry in sync.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            attempt: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        delay / 2 + (delay / 2).mul_f64(random_fraction())
+    }
+
+    /// Starts over from `initial`, once a connection got established.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// A number in `[0, 1)`. Good enough for jitter without pulling in `rand`,
+/// as every `RandomState` is seeded differently.
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let bits = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    /// Authenticated and subscribed to state changes.
+    Connected,
+    /// Waiting for the next attempt after the connection failed or dropped.
+    Reconnecting,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connecting => "connecting",
+            Self::Connected => "connected",
+            Self::Reconnecting => "reconnecting",
+        }
+    }
+}
+
+/// State of the WebSocket connection, as seen by the heartbeat.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionHealth {
+    pub state: ConnectionState,
+    /// Round trip time of the last heartbeat.
+    pub latency: Option<Duration>,
+    pub last_pong: Option<DateTime<Utc>>,
+}
+
+impl ConnectionHealth {
+    /// Connected and answering heartbeats.
+    pub fn healthy(&self) -> bool {
+        self.state == ConnectionState::Connected
+    }
+}
+
+/// The bridged players' way to HA: commands go out through `commands`, the
+/// state of the connection comes back through `health`.
+#[derive(Debug, Clone)]
+pub struct HAConnection {
+    pub commands: Sender<(String, HAEvent, CommandReply)>,
+    pub health: watch::Receiver<ConnectionHealth>,
+}
+
+/// TLS options shared by the HTTP client and the WebSocket connection.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSettings {
+    /// PEM bundle with extra CA certificates to trust, e.g. a private CA.
+    pub ca_bundle: Option<PathBuf>,
+    /// Skip certificate and hostname verification. Only meant for self-signed setups.
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsSettings {
+    fn tls_connector(&self) -> Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .danger_accept_invalid_hostnames(self.accept_invalid_certs);
+        if let Some(path) = &self.ca_bundle {
+            let bundle = std::fs::read_to_string(path)
+                .map_err(|e| eyre::eyre!("Could not read CA bundle {}: {e}", path.display()))?;
+            for pem in bundle
+                .split_inclusive("-----END CERTIFICATE-----")
+                .filter(|pem| pem.contains("-----BEGIN CERTIFICATE-----"))
+            {
+                builder.add_root_certificate(native_tls::Certificate::from_pem(pem.as_bytes())?);
+            }
+        }
+        Ok(builder.build()?)
+    }
+
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        Ok(reqwest::Client::builder()
+            .use_preconfigured_tls(self.tls_connector()?)
+            .connect_timeout(HTTP_CONNECT_TIMEOUT)
+            .timeout(HTTP_TIMEOUT)
+            .build()?)
+    }
+
+    pub fn websocket_connector(&self) -> Result<Connector> {
+        Ok(Connector::NativeTls(self.tls_connector()?))
+    }
+}
+
+/// Derives the WebSocket API endpoint from the configured HA URL, keeping the
+/// scheme's security (https -> wss) and any reverse proxy path prefix.
+pub fn websocket_url(ha_url: &str) -> Result<Url> {
+    let mut url = Url::parse(ha_url)?;
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        other => eyre::bail!("Unsupported scheme '{other}' in HA URL, expected http or https"),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| eyre::eyre!("Can not convert {ha_url} to a WebSocket URL"))?;
+    let path = format!("{}/api/websocket", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok(url)
+}
+
 #[derive(Debug, Clone)]
 pub struct MediaPlayerMetadata {
+    /// The entity's display name in HA.
+    pub friendly_name: Option<String>,
     pub title: String,
-    pub artist: String,
-    pub duration: i64,
-    pub position: i64,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub album_artists: Vec<String>,
+    pub track_number: Option<i32>,
+    /// Show name, season and episode for TV content.
+    pub series: Option<String>,
+    pub season: Option<String>,
+    pub episode: Option<String>,
+    /// TV or radio channel.
+    pub channel: Option<String>,
+    pub playlist: Option<String>,
+    /// The app playing the media, e.g. `Spotify` or `Netflix`.
+    pub app_name: Option<String>,
+    /// The selected input or service, e.g. `Spotify` or `TV`.
+    pub source: Option<String>,
+    /// HA's id for what is playing, often a URL or a `spotify:` style URI.
+    pub content_id: Option<String>,
+    /// Track length in seconds.
+    pub duration: f64,
+    /// Playback position in seconds, as last reported by HA.
+    pub position: f64,
+    /// When HA last reported `position`. HA only refreshes the position on
+    /// discontinuities, so the live position is extrapolated from this.
+    pub position_updated_at: Option<DateTime<Utc>>,
+    /// Playback speed. HA does not expose one, so media plays at normal speed.
+    pub rate: f64,
+    /// Volume level, which HA keeps while muted.
     pub volume: f64,
+    pub muted: bool,
     pub art_url: String,
-    pub playing: bool,
+    pub state: HAState,
     pub shuffle: bool,
     pub repeat: HALoopStatus,
+    pub features: SupportedFeatures,
+    /// Index of the playing item in the player's queue, if the integration reports it.
+    pub queue_position: Option<usize>,
+    pub queue_size: Option<usize>,
 }
 
-#[derive(Debug, Clone)]
+/// The `supported_features` bitmask of a HA media player
+/// (`MediaPlayerEntityFeature` upstream).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SupportedFeatures(u64);
+
+impl SupportedFeatures {
+    pub const PAUSE: Self = Self(1);
+    pub const SEEK: Self = Self(1 << 1);
+    pub const VOLUME_SET: Self = Self(1 << 2);
+    pub const PREVIOUS_TRACK: Self = Self(1 << 4);
+    pub const NEXT_TRACK: Self = Self(1 << 5);
+    pub const VOLUME_MUTE: Self = Self(1 << 3);
+    pub const TURN_OFF: Self = Self(1 << 8);
+    pub const PLAY_MEDIA: Self = Self(1 << 9);
+    pub const VOLUME_STEP: Self = Self(1 << 10);
+    pub const STOP: Self = Self(1 << 12);
+    pub const PLAY: Self = Self(1 << 14);
+    pub const BROWSE_MEDIA: Self = Self(1 << 17);
+    pub const SHUFFLE_SET: Self = Self(1 << 15);
+    pub const REPEAT_SET: Self = Self(1 << 18);
+    pub const MEDIA_ENQUEUE: Self = Self(1 << 21);
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn contains(&self, feature: Self) -> bool {
+        self.0 & feature.0 == feature.0
+    }
+}
+
+impl std::ops::BitOr for SupportedFeatures {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl MediaPlayerMetadata {
+    /// Position in seconds at the given time, extrapolated while playing.
+    pub fn position_at(&self, at: DateTime<Utc>) -> f64 {
+        let mut position = self.position;
+        if let (HAState::Playing, Some(updated_at)) = (self.state, self.position_updated_at) {
+            let elapsed = (at - updated_at).num_microseconds().unwrap_or(0) as f64 / 1_000_000.0;
+            position += elapsed.max(0.0) * self.rate;
+        }
+        if self.duration > 0.0 {
+            position = position.min(self.duration);
+        }
+        position.max(0.0)
+    }
+
+    pub fn current_position(&self) -> f64 {
+        self.position_at(Utc::now())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HALoopStatus {
     None,
     Track,
     Playlist,
 }
 
+/// The state of a HA media player entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HAState {
+    Off,
+    On,
+    Idle,
+    Standby,
+    Buffering,
+    Playing,
+    Paused,
+    Unavailable,
+    Unknown,
+}
+
+impl From<&str> for HAState {
+    fn from(state: &str) -> Self {
+        match state {
+            "off" => Self::Off,
+            "on" => Self::On,
+            "idle" => Self::Idle,
+            "standby" => Self::Standby,
+            "buffering" => Self::Buffering,
+            "playing" => Self::Playing,
+            "paused" => Self::Paused,
+            "unavailable" => Self::Unavailable,
+            _ => Self::Unknown,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum HAEvent {
     Play,
     Pause,
-    MetadataUpdated(MediaPlayerMetadata),
+    Stop,
+    TurnOff,
+    MetadataUpdated(Box<MediaPlayerMetadata>),
     Next,
     Previous,
     Volume(f64),
+    Mute(bool),
+    VolumeUp,
+    VolumeDown,
     SetShuffle(bool),
     SetLoop(HALoopStatus),
-    Seek(i64),
+    /// Absolute position to seek to, in seconds.
+    Seek(f64),
+    PlayMedia(MediaRequest),
+    Enqueue(MediaRequest, Enqueue),
+    GetQueue(QueueSource),
+    PlayQueueItem(QueueSource, usize),
+    RemoveQueueItem(QueueSource, usize),
+    /// Lists the children of a media node, or the root without one.
+    BrowseMedia(Option<MediaRequest>),
+}
+
+/// Reads an attribute as text, accepting numbers too as HA integrations are
+/// not consistent about e.g. `media_season`. Empty values count as missing.
+fn text_attribute(metadata: &HashMap<String, Value>, key: &str) -> Option<String> {
+    match metadata.get(key)? {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Splits strings like `Artist A; Artist B feat. Artist C` into single
+/// artists. Commas are left alone, as names like `Earth, Wind & Fire` or
+/// `Tyler, the Creator` have them too.
+pub fn split_artists(artists: &str) -> Vec<String> {
+    let mut split = vec![artists.to_string()];
+    for separator in [";", " / ", " feat. ", " ft. ", " featuring "] {
+        split = split
+            .iter()
+            .flat_map(|a| a.split(separator))
+            .map(str::to_string)
+            .collect();
+    }
+    split
+        .into_iter()
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect()
 }
 
 pub fn json_to_metadata(
     metadata: HashMap<String, serde_json::Value>,
-    playing: bool,
+    state: HAState,
     base_url: String,
 ) -> Result<MediaPlayerMetadata> {
     Ok(MediaPlayerMetadata {
-        title: metadata
-            .get("media_title")
-            .unwrap_or(&Value::String("".to_string()))
-            .to_string()
-            .trim_matches(['\"'])
-            .to_string(),
-        artist: metadata
-            .get("media_artist")
-            .unwrap_or(&Value::String("".to_string()))
-            .to_string()
-            .trim_matches(['\"'])
-            .to_string(),
+        title: text_attribute(&metadata, "media_title").unwrap_or_default(),
+        artists: split_artists(&text_attribute(&metadata, "media_artist").unwrap_or_default()),
+        album: text_attribute(&metadata, "media_album_name"),
+        album_artists: split_artists(
+            &text_attribute(&metadata, "media_album_artist").unwrap_or_default(),
+        ),
+        track_number: metadata
+            .get("media_track")
+            .and_then(|v| v.as_i64())
+            .and_then(|v| i32::try_from(v).ok()),
+        series: text_attribute(&metadata, "media_series_title"),
+        season: text_attribute(&metadata, "media_season"),
+        episode: text_attribute(&metadata, "media_episode"),
+        channel: text_attribute(&metadata, "media_channel"),
+        playlist: text_attribute(&metadata, "media_playlist"),
+        app_name: text_attribute(&metadata, "app_name"),
+        friendly_name: text_attribute(&metadata, "friendly_name"),
+        source: text_attribute(&metadata, "source"),
+        content_id: text_attribute(&metadata, "media_content_id"),
         duration: metadata
             .get("media_duration")
             .unwrap_or(&json!(0))
-            .as_i64()
-            .ok_or_eyre("Could not convert Number to i64")?,
+            .as_f64()
+            .ok_or_eyre("Could not convert Number to f64")?,
         position: metadata
             .get("media_position")
             .unwrap_or(&json!(0))
-            .as_i64()
-            .ok_or_eyre("Could not convert Number to i64")?,
-        art_url: validate_art_url(
-            metadata
-                .get("entity_picture")
-                .unwrap_or(&Value::String("".to_string()))
-                .to_string()
-                .trim_matches(['\"'])
-                .to_string(),
-            &base_url,
-        )?
-        .to_string(),
+            .as_f64()
+            .ok_or_eyre("Could not convert Number to f64")?,
+        position_updated_at: match metadata
+            .get("media_position_updated_at")
+            .and_then(|v| v.as_str())
+        {
+            Some(t) => Some(DateTime::parse_from_rfc3339(t)?.with_timezone(&Utc)),
+            None => None,
+        },
+        rate: 1.0,
+        art_url: match text_attribute(&metadata, "entity_picture") {
+            Some(picture) => validate_art_url(picture, &base_url)?.to_string(),
+            None => "".to_string(),
+        },
         volume: metadata
             .get("volume_level")
             .unwrap_or(&json!(1.0))
             .as_f64()
             .ok_or_eyre("Could not convert Number to f64")?,
-        playing,
-        repeat: match metadata
-            .get("repeat")
-            .unwrap_or(&json!("off"))
-            .to_string()
-            .trim_matches(['\"'])
-        {
-            "one" => HALoopStatus::Track,
-            "all" => HALoopStatus::Playlist,
+        muted: metadata
+            .get("is_volume_muted")
+            .and_then(Value::as_bool)
+            .unwrap_or_default(),
+        state,
+        repeat: match metadata.get("repeat").and_then(Value::as_str) {
+            Some("one") => HALoopStatus::Track,
+            Some("all") => HALoopStatus::Playlist,
             _ => HALoopStatus::None,
         },
         shuffle: metadata
@@ -118,53 +563,80 @@ pub fn json_to_metadata(
             .unwrap_or(&json!(false))
             .as_bool()
             .ok_or_eyre("Could not convert Bool to boolean")?,
+        features: SupportedFeatures::from_bits(
+            metadata
+                .get("supported_features")
+                .unwrap_or(&json!(0))
+                .as_u64()
+                .ok_or_eyre("Could not convert Number to u64")?,
+        ),
+        queue_position: metadata
+            .get("queue_position")
+            .and_then(Value::as_u64)
+            .map(|p| p as usize),
+        queue_size: metadata
+            .get("queue_size")
+            .and_then(Value::as_u64)
+            .map(|s| s as usize),
     })
 }
 
 impl MediaPlayerState {
-    pub fn new(entity_id: String, ha_url: String, ha_token: String) -> Self {
-        Self {
-            ha_token,
-            ha_url,
-            entity_id,
-        }
+    pub fn new(entity_id: String, ha_url: String) -> Self {
+        Self { ha_url, entity_id }
     }
 
-    pub async fn play(&self) -> Result<()> {
-        self.send_command_to_home_assistant("media_play", None)
-            .await
+    pub fn play(&self) -> Value {
+        self.service_call("media_play", None)
     }
 
-    pub async fn pause(&self) -> Result<()> {
-        self.send_command_to_home_assistant("media_pause", None)
-            .await
+    pub fn pause(&self) -> Value {
+        self.service_call("media_pause", None)
     }
 
-    pub async fn next(&self) -> Result<()> {
-        self.send_command_to_home_assistant("media_next_track", None)
-            .await
+    pub fn stop(&self) -> Value {
+        self.service_call("media_stop", None)
     }
 
-    pub async fn previous(&self) -> Result<()> {
-        self.send_command_to_home_assistant("media_previous_track", None)
-            .await
+    pub fn turn_off(&self) -> Value {
+        self.service_call("turn_off", None)
     }
 
-    pub async fn set_volume(&self, volume: f64) -> Result<()> {
+    pub fn next(&self) -> Value {
+        self.service_call("media_next_track", None)
+    }
+
+    pub fn previous(&self) -> Value {
+        self.service_call("media_previous_track", None)
+    }
+
+    pub fn set_volume(&self, volume: f64) -> Value {
         let mut extras = serde_json::Map::new();
         extras.insert("volume_level".to_string(), json!(volume));
-        self.send_command_to_home_assistant("volume_set", Some(extras))
-            .await
+        self.service_call("volume_set", Some(extras))
     }
 
-    pub async fn set_shuffle(&self, shuffle: bool) -> Result<()> {
+    pub fn set_mute(&self, muted: bool) -> Value {
+        let mut extras = serde_json::Map::new();
+        extras.insert("is_volume_muted".to_string(), json!(muted));
+        self.service_call("volume_mute", Some(extras))
+    }
+
+    pub fn volume_up(&self) -> Value {
+        self.service_call("volume_up", None)
+    }
+
+    pub fn volume_down(&self) -> Value {
+        self.service_call("volume_down", None)
+    }
+
+    pub fn set_shuffle(&self, shuffle: bool) -> Value {
         let mut extras = serde_json::Map::new();
         extras.insert("shuffle".to_string(), json!(shuffle));
-        self.send_command_to_home_assistant("shuffle_set", Some(extras))
-            .await
+        self.service_call("shuffle_set", Some(extras))
     }
 
-    pub async fn set_loop(&self, loop_status: HALoopStatus) -> Result<()> {
+    pub fn set_loop(&self, loop_status: HALoopStatus) -> Value {
         let mut extras = serde_json::Map::new();
         extras.insert(
             "repeat".to_string(),
@@ -174,51 +646,109 @@ impl MediaPlayerState {
                 HALoopStatus::Playlist => "all",
             }),
         );
-        self.send_command_to_home_assistant("repeat_set", Some(extras))
-            .await
+        self.service_call("repeat_set", Some(extras))
     }
 
-    pub async fn set_seek(&self, position: i64) -> Result<()> {
+    pub fn set_seek(&self, position: f64) -> Value {
         let mut extras = serde_json::Map::new();
         extras.insert("seek_position".to_string(), json!(position));
-        self.send_command_to_home_assistant("media_seek", Some(extras))
-            .await
+        self.service_call("media_seek", Some(extras))
     }
 
-    pub async fn update_metadata(
-        &self,
-        metadata: serde_json::value::Value,
-        state: String,
-    ) -> Result<Vec<HAEvent>> {
-        let mut events = vec![];
-
-        if state.eq("\"playing\"") {
-            events.push(HAEvent::Play);
-        } else {
-            events.push(HAEvent::Pause);
+    pub fn play_media(&self, media: MediaRequest) -> Value {
+        let mut extras = serde_json::Map::new();
+        extras.insert("media_content_id".to_string(), json!(media.content_id));
+        extras.insert("media_content_type".to_string(), json!(media.content_type));
+        self.service_call("play_media", Some(extras))
+    }
+
+    pub fn enqueue(&self, media: MediaRequest, enqueue: Enqueue) -> Value {
+        let mut call = self.play_media(media);
+        call["service_data"]["enqueue"] = json!(enqueue.as_str());
+        call
+    }
+
+    pub fn get_queue(&self, source: QueueSource) -> Value {
+        let mut call = self.integration_service_call(source.domain(), "get_queue", None);
+        call["return_response"] = json!(true);
+        call
+    }
+
+    pub fn play_queue_item(&self, source: QueueSource, index: usize) -> Value {
+        let mut extras = serde_json::Map::new();
+        extras.insert("queue_position".to_string(), json!(index));
+        self.integration_service_call(source.domain(), "play_queue", Some(extras))
+    }
+
+    pub fn remove_queue_item(&self, source: QueueSource, index: usize) -> Value {
+        let mut extras = serde_json::Map::new();
+        extras.insert("queue_position".to_string(), json!(index));
+        self.integration_service_call(source.domain(), "remove_from_queue", Some(extras))
+    }
+
+    /// `media_player/browse_media` is a WebSocket command of its own, not a service.
+    pub fn browse_media(&self, media: Option<MediaRequest>) -> Value {
+        let mut command = json!({
+            "type": "media_player/browse_media",
+            "entity_id": self.entity_id,
+        });
+        if let Some(media) = media {
+            command["media_content_id"] = json!(media.content_id);
+            command["media_content_type"] = json!(media.content_type);
         }
-        let attribs: HashMap<String, serde_json::Value> =
-            if let serde_json::Value::Object(m) = metadata {
-                m.into_iter().collect()
-            } else {
-                eyre::bail!("Oh no");
-            };
-        events.push(HAEvent::MetadataUpdated(json_to_metadata(
-            attribs,
-            state.contains("playing"),
+        command
+    }
+
+    /// Maps an MPRIS request onto the matching `call_service` message, without an id.
+    pub fn command(&self, event: HAEvent) -> Option<Value> {
+        Some(match event {
+            HAEvent::Play => self.play(),
+            HAEvent::Pause => self.pause(),
+            HAEvent::Stop => self.stop(),
+            HAEvent::TurnOff => self.turn_off(),
+            HAEvent::Next => self.next(),
+            HAEvent::Previous => self.previous(),
+            HAEvent::Volume(v) => self.set_volume(v),
+            HAEvent::Mute(m) => self.set_mute(m),
+            HAEvent::VolumeUp => self.volume_up(),
+            HAEvent::VolumeDown => self.volume_down(),
+            HAEvent::SetShuffle(s) => self.set_shuffle(s),
+            HAEvent::SetLoop(l) => self.set_loop(l),
+            HAEvent::Seek(p) => self.set_seek(p),
+            HAEvent::PlayMedia(m) => self.play_media(m),
+            HAEvent::Enqueue(m, e) => self.enqueue(m, e),
+            HAEvent::GetQueue(source) => self.get_queue(source),
+            HAEvent::PlayQueueItem(source, i) => self.play_queue_item(source, i),
+            HAEvent::RemoveQueueItem(source, i) => self.remove_queue_item(source, i),
+            HAEvent::BrowseMedia(m) => self.browse_media(m),
+            HAEvent::MetadataUpdated(_) => return None,
+        })
+    }
+
+    pub fn update_metadata(&self, player: &MediaPlayer) -> Result<HAEvent> {
+        let state = HAState::from(player.state.as_str());
+        Ok(HAEvent::MetadataUpdated(Box::new(json_to_metadata(
+            player.attributes.clone(),
+            state,
             self.ha_url.clone(),
-        )?));
-        Ok(events)
+        )?)))
     }
 
-    pub async fn send_command_to_home_assistant(
+    pub fn service_call(
         &self,
-        command: &str,
+        service: &str,
         extra_params: Option<serde_json::Map<String, Value>>,
-    ) -> Result<()> {
-        let client = reqwest::Client::new();
-        let url = format!("{}/api/services/media_player/{}", self.ha_url, command);
+    ) -> Value {
+        self.integration_service_call("media_player", service, extra_params)
+    }
 
+    /// Like `service_call`, for services an integration registers in its own domain.
+    pub fn integration_service_call(
+        &self,
+        domain: &str,
+        service: &str,
+        extra_params: Option<serde_json::Map<String, Value>>,
+    ) -> Value {
         let mut params = serde_json::Map::new();
         params.insert(
             "entity_id".to_string(),
@@ -231,150 +761,936 @@ impl MediaPlayerState {
             }
         }
 
-        client
-            .post(url)
-            .header("Authorization", format!("Bearer {}", self.ha_token))
-            .json(&params)
-            .send()
-            .await
-            .expect("AAAH");
+        json!({
+            "type": "call_service",
+            "domain": domain,
+            "service": service,
+            "service_data": params,
+        })
+    }
+}
 
-        Ok(())
+/// Sends a command and waits for its result. Only meant for connections
+/// without active subscriptions, as any other message is skipped.
+async fn request(ws: &mut WsStream, id: u64, mut command: Value) -> Result<Value, BridgeError> {
+    command["id"] = json!(id);
+    ws.send(Message::Text(command.to_string())).await?;
+    while let Some(message) = ws.next().await {
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        let mut response: Value = serde_json::from_str(&text)?;
+        if response["type"] == "result" && response["id"] == id {
+            if response["success"] == true {
+                return Ok(response["result"].take());
+            }
+            return Err(BridgeError::Protocol(format!(
+                "{} failed: {}",
+                command["type"],
+                ServiceCallError::from_result(&response)
+            )));
+        }
     }
+    Err(BridgeError::Transport(format!(
+        "Connection closed while waiting for {}",
+        command["type"]
+    )))
 }
 
-pub async fn get_media_players(
-    home_assistant_url: &str,
-    token: &str,
-    entity_ids: Vec<String>,
-) -> Result<Vec<MediaPlayer>> {
-    let client = Client::new();
-    let url = format!("{}/api/states", home_assistant_url);
-    let response = client
-        .get(&url)
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
+/// Answers commands from the MPRIS side with an error until `deadline`, so
+/// they fail right away instead of timing out while HA is unreachable.
+pub async fn reject_commands_until(
+    mpris_rx: &mut Receiver<(String, HAEvent, CommandReply)>,
+    deadline: Instant,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            Some((entity_id, _, reply)) = mpris_rx.recv() => {
+                let _ = reply.send(Err(ServiceCallError::new(
+                    "not_connected",
+                    format!("Not connected to Home Assistant, can not control {entity_id}"),
+                )));
+            }
+        }
+    }
+}
+
+/// Opens a WebSocket connection and authenticates.
+async fn connect(connection: &ConnectionSettings) -> Result<WsStream, BridgeError> {
+    let (mut ws_stream, _) = connect_async_tls_with_config(
+        connection.url.as_str(),
+        None,
+        false,
+        Some(connection.connector.clone()),
+    )
+    .await?;
+    let auth_message = json!({
+        "type": "auth",
+        "access_token": connection.access_token
+    });
+    ws_stream
+        .send(Message::Text(auth_message.to_string()))
         .await?;
+    loop {
+        let text = match ws_stream.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => {
+                return Err(BridgeError::Transport(
+                    "Connection closed during authentication".to_string(),
+                ))
+            }
+        };
+        let response: Value = serde_json::from_str(&text)?;
+        match response["type"].as_str() {
+            Some("auth_ok") => return Ok(ws_stream),
+            Some("auth_invalid") => {
+                return Err(BridgeError::Auth {
+                    message: response["message"]
+                        .as_str()
+                        .unwrap_or("no reason given")
+                        .to_string(),
+                    source: connection.token_source.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+}
 
-    let media_players: Vec<MediaPlayer> = response
-        .json::<Vec<MediaPlayer>>()
-        .await?
+/// Fetches the registries and the state of every entity, using message ids
+/// after `next_id`. Registries HA can not list are left empty, as only area
+/// and label selectors need them.
+async fn snapshot(
+    ws_stream: &mut WsStream,
+    next_id: &mut u64,
+) -> Result<(Registry, Vec<MediaPlayer>), BridgeError> {
+    let mut registry = Registry::default();
+    for kind in RegistryKind::ALL {
+        *next_id += 1;
+        match request(ws_stream, *next_id, json!({"type": kind.list_command()})).await {
+            Ok(list) => registry.update(kind, &list),
+            Err(BridgeError::Protocol(e)) => {
+                println!("Ignoring {}: {e}", kind.list_command());
+                registry.mark_missing(kind);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    *next_id += 1;
+    let states = request(ws_stream, *next_id, json!({"type": "get_states"})).await?;
+    let players = serde_json::from_value::<Vec<MediaPlayer>>(states)?
         .into_iter()
         .filter(|m| m.entity_id.starts_with("media_player."))
-        .filter(|d| entity_ids.contains(&d.entity_id))
         .collect();
+    Ok((registry, players))
+}
 
-    Ok(media_players)
+/// Connects once to look up every media player in HA, bridged or not.
+pub async fn fetch_media_players(
+    connection: &ConnectionSettings,
+) -> Result<(Registry, Vec<MediaPlayer>), BridgeError> {
+    let mut ws_stream = connect(connection).await?;
+    let snapshot = snapshot(&mut ws_stream, &mut 0).await;
+    let _ = ws_stream.close(None).await;
+    snapshot
 }
 
 pub async fn listen_for_events(
-    ha_url: String,
-    access_token: String,
-    mut media_players: HashMap<String, MediaPlayerState>,
-    channels: HashMap<String, Sender<HAEvent>>,
-    mpris_rx: Arc<Mutex<Receiver<(String, HAEvent)>>>,
-) -> Result<()> {
-    let (ws_stream, _) = connect_async(ha_url).await?;
-    let (mut write, mut read) = ws_stream.split();
-    let auth_message = json!({
-        "type": "auth",
-        "access_token": access_token
-    });
-    write.send(Message::Text(auth_message.to_string())).await?;
-    while let Some(Ok(message)) = read.next().await {
-        if let Message::Text(text) = message {
-            let response: serde_json::Value = serde_json::from_str(&text).expect("Invalid JSON");
-            if response["type"] == "auth_ok" {
-                println!("Authenticated successfully!");
-                break;
-            } else if response["type"] == "auth_invalid" {
-                eprintln!("Authentication failed: {}", response["message"]);
-            }
-        }
+    connection: &ConnectionSettings,
+    players: &mut Players,
+    mpris_rx: &mut Receiver<(String, HAEvent, CommandReply)>,
+    selection: &mut watch::Receiver<(Vec<EntitySelector>, SettingsByEntity)>,
+    health: &watch::Sender<ConnectionHealth>,
+) -> Result<(), BridgeError> {
+    let heartbeat = connection.heartbeat;
+    let mut ws_stream = connect(connection).await?;
+
+    // Look up which media players exist and where they live before following changes
+    let mut next_id: u64 = 0;
+    let (registry, states) = snapshot(&mut ws_stream, &mut next_id).await?;
+    if selection.has_changed().unwrap_or_default() {
+        // The config got reloaded while disconnected
+        let (selectors, settings) = selection.borrow_and_update().clone();
+        players.reconfigure(selectors, settings);
     }
+    players.reset(registry, states);
+    players.check_registry()?;
+    // Players that stayed bridged across a reconnect missed every change in between
+    players.publish_all().await;
 
-    let subscribe_message = json!({
-        "id": 1,
-        "type": "subscribe_events",
-        "event_type": "state_changed",
-    });
+    let (mut write, mut read) = ws_stream.split();
+    let event_types = std::iter::once("state_changed")
+        .chain(RegistryKind::ALL.iter().map(|kind| kind.updated_event()));
+    for event_type in event_types {
+        next_id += 1;
+        let subscribe_message = json!({
+            "id": next_id,
+            "type": "subscribe_events",
+            "event_type": event_type,
+        });
+        write
+            .send(Message::Text(subscribe_message.to_string()))
+            .await?;
+    }
 
-    write
-        .send(Message::Text(subscribe_message.to_string()))
-        .await
-        .expect("Failed to send subscribe message");
+    // Service calls waiting for their `result` message, keyed by message id
+    let mut pending: HashMap<u64, CommandReply> = HashMap::new();
+    // Registry refreshes waiting for their `result` message
+    let mut registry_requests: HashMap<u64, RegistryKind> = HashMap::new();
+    // The ping waiting for its pong, with when it was sent
+    let mut ping: Option<(u64, Instant)> = None;
+    let mut next_ping = Instant::now() + heartbeat.interval;
+    health.send_modify(|h| h.state = ConnectionState::Connected);
+    println!("Connected to Home Assistant");
 
     loop {
         tokio::select! {
             event = read.next() => {
                 let text = match event {
                     Some(Ok(Message::Text(t))) => t,
-                    Some(Ok(Message::Close(_))) => break Err(eyre::eyre!("Channel closed")),
-                    None => break Err(eyre::eyre!("Restarting websocket channel due to unknown reason")),
+                    Some(Ok(Message::Close(_))) => {
+                        break Err(BridgeError::Transport("Home Assistant closed the connection".to_string()))
+                    }
+                    Some(Err(e)) => break Err(e.into()),
+                    None => break Err(BridgeError::Transport("Connection ended unexpectedly".to_string())),
                     _ => continue
                 };
-                let Ok(event): Result<serde_json::Value, Error> = serde_json::from_str(&text) else { continue };
-                let Some(entity_id) = event.get("event").and_then(|e| e.get("data")).and_then(|d| d.get("entity_id")).and_then(|e| e.as_str()) else { continue };
-                let Some(media_player) = media_players.get_mut(entity_id) else { continue };
-                let Some(new_state) = event.get("event").and_then(|e| e.get("data")).and_then(|d| d.get("new_state")) else { continue };
-                let Some(attr) = new_state.get("attributes") else { continue };
-                let Some(state) = new_state.get("state") else { continue };
-                match media_player
-                    .update_metadata(
-                        attr.clone(),
-                        state.to_string().clone(),
-                    )
-                    .await {
-                    Ok(events) => {
-                        for e in events {
-                            channels
-                                .get(entity_id)
-                                .unwrap()
-                                .send(e)
-                                .await?;
+                let Ok(mut event): Result<serde_json::Value, Error> = serde_json::from_str(&text) else { continue };
+                if event["type"] == "pong" {
+                    if let Some((_, sent_at)) = ping.filter(|(id, _)| event["id"] == *id) {
+                        ping = None;
+                        health.send_modify(|h| {
+                            h.latency = Some(sent_at.elapsed());
+                            h.last_pong = Some(Utc::now());
+                        });
+                    }
+                    continue;
+                }
+                if event["type"] == "result" {
+                    let Some(id) = event["id"].as_u64() else { continue };
+                    if let Some(reply) = pending.remove(&id) {
+                        let _ = reply.send(if event["success"] == true {
+                            Ok(event["result"].take())
+                        } else {
+                            Err(ServiceCallError::from_result(&event))
+                        });
+                    } else if let Some(kind) = registry_requests.remove(&id) {
+                        if event["success"] == true {
+                            players.update_registry(kind, &event["result"]);
+                        } else {
+                            println!("Could not refresh {}: {}", kind.list_command(), ServiceCallError::from_result(&event));
                         }
-                    },
-                    Err(e) => println!("Died during metadata update event with {e}"),
-                };
+                    }
+                    continue;
+                }
+                let Some(event_type) = event["event"]["event_type"].as_str() else { continue };
+                if let Some(kind) = RegistryKind::from_event(event_type) {
+                    next_id += 1;
+                    let list_message = json!({"id": next_id, "type": kind.list_command()});
+                    write.send(Message::Text(list_message.to_string())).await?;
+                    registry_requests.insert(next_id, kind);
+                    continue;
+                }
+                let Some(entity_id) = event["event"]["data"]["entity_id"].as_str() else { continue };
+                if !entity_id.starts_with("media_player.") { continue };
+                let new_state = &event["event"]["data"]["new_state"];
+                if new_state.is_null() {
+                    players.set_state(entity_id, None);
+                    continue;
+                }
+                let Ok(new_state) = serde_json::from_value::<MediaPlayer>(new_state.clone()) else { continue };
+                players.set_state(entity_id, Some(new_state));
+                players.publish(entity_id).await;
+            }
+
+            Ok(()) = selection.changed() => {
+                let (selectors, settings) = selection.borrow_and_update().clone();
+                players.reconfigure(selectors, settings);
+                if let Err(e) = players.check_registry() {
+                    println!("{e}");
+                }
             }
 
-            result = async {
-                let mut guard = mpris_rx.lock().await;
-                guard.recv().await
-            } => {
-                let Some((entity_id, msg)) = result else { continue };
-                let media = media_players.get_mut(&entity_id);
-                if let Some(mp) = media {
-                    match msg {
-                        HAEvent::Play=> mp.play().await?,
-                        HAEvent::Pause=> mp.pause().await?,
-                        HAEvent::Next => mp.next().await?,
-                        HAEvent::Previous => mp.previous().await?,
-                        HAEvent::Volume(v) => mp.set_volume(v).await?,
-                        HAEvent::SetShuffle(s) => mp.set_shuffle(s).await?,
-                        HAEvent::SetLoop(l) => mp.set_loop(l).await?,
-                        HAEvent::Seek(p) => mp.set_seek(p).await?,
-                        _ => {},
-                    };
+            result = mpris_rx.recv() => {
+                let Some((entity_id, msg, reply)) = result else { continue };
+                let Some(mp) = players.get(&entity_id).map(|p| &p.state) else {
+                    let _ = reply.send(Err(ServiceCallError::new(
+                        "not_found",
+                        format!("{entity_id} is not bridged"),
+                    )));
+                    continue;
+                };
+                let Some(mut call) = mp.command(msg) else {
+                    let _ = reply.send(Ok(Value::Null));
+                    continue;
+                };
+                next_id += 1;
+                call["id"] = json!(next_id);
+                if let Err(e) = write.send(Message::Text(call.to_string())).await {
+                    let _ = reply.send(Err(ServiceCallError::new(
+                        "connection_lost",
+                        format!("Could not send command to Home Assistant: {e}"),
+                    )));
+                    break Err(e.into());
                 }
+                pending.insert(next_id, reply);
+            }
+
+            _ = tokio::time::sleep_until(next_ping), if ping.is_none() => {
+                next_id += 1;
+                write.send(Message::Text(json!({"id": next_id, "type": "ping"}).to_string())).await?;
+                ping = Some((next_id, Instant::now()));
+                next_ping = Instant::now() + heartbeat.interval;
             }
 
-            _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                break Err(eyre::eyre!("Timed out"));
+            _ = tokio::time::sleep_until(ping.map_or(next_ping, |(_, sent_at)| sent_at + heartbeat.timeout)), if ping.is_some() => {
+                break Err(BridgeError::Transport(format!(
+                    "Home Assistant did not answer the heartbeat within {}s",
+                    heartbeat.timeout.as_secs()
+                )));
             }
         }
     }
 }
 
-fn validate_art_url(art_url: String, base_url: &str) -> eyre::Result<Url> {
+/// Resolves art URLs relative to HA. HA hands out paths from its root, like
+/// `/api/media_player_proxy/...`, which sits below any reverse proxy path
+/// prefix of `base_url`.
+pub fn validate_art_url(art_url: String, base_url: &str) -> eyre::Result<Url> {
     let parsed_url = url::Url::parse(&art_url);
 
     match parsed_url {
         Ok(url) => Ok(url),
         Err(url::ParseError::RelativeUrlWithoutBase) => {
-            let base = url::Url::parse(base_url)?;
-            base.join(&art_url).map_err(|_e| eyre::eyre!("Oh no"))
+            let mut base = url::Url::parse(base_url)?;
+            let joined = match art_url.strip_prefix('/') {
+                Some(path) if !path.starts_with('/') => {
+                    let prefix = format!("{}/", base.path().trim_end_matches('/'));
+                    base.set_path(&prefix);
+                    base.join(path)
+                }
+                _ => base.join(&art_url),
+            };
+            joined.map_err(|e| eyre::eyre!("Invalid art URL {art_url}: {e}"))
+        }
+        Err(e) => Err(eyre::eyre!("Invalid art URL {art_url}: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, sync::Arc};
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+    use tokio_native_tls::TlsStream;
+    use tokio_tungstenite::{
+        accept_async, accept_hdr_async, tungstenite::handshake::server::Request,
+    };
+
+    use super::*;
+    use crate::art::{ArtCache, ArtCacheSettings};
+
+    type ServerStream<S = TcpStream> = WebSocketStream<S>;
+
+    /// Signed the certificate the TLS stand-in uses for 127.0.0.1 and localhost.
+    const CA_BUNDLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem");
+
+    fn connection_settings(url: Url, connector: Connector) -> ConnectionSettings {
+        ConnectionSettings {
+            url,
+            connector,
+            access_token: "token".to_string(),
+            token_source: TokenSource::Environment,
+            heartbeat: Heartbeat {
+                interval: Duration::from_secs(60),
+                timeout: Duration::from_secs(10),
+            },
+        }
+    }
+
+    /// Serves a single WebSocket connection on localhost, with `handler`
+    /// standing in for HA.
+    async fn stand_in<F, Fut>(handler: F) -> ConnectionSettings
+    where
+        F: FnOnce(ServerStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handler(accept_async(stream).await.unwrap()).await;
+        });
+        connection_settings(
+            websocket_url(&format!("http://127.0.0.1:{port}")).unwrap(),
+            Connector::Plain,
+        )
+    }
+
+    /// Accepts a single TLS connection on localhost and hands it to `handler`,
+    /// unless the client rejects the certificate.
+    async fn tls_stand_in<F, Fut>(handler: F) -> u16
+    where
+        F: FnOnce(TlsStream<TcpStream>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../testdata/localhost.pem"),
+            include_bytes!("../testdata/localhost-key.pem"),
+        )
+        .unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(stream) = acceptor.accept(stream).await {
+                handler(stream).await;
+            }
+        });
+        port
+    }
+
+    /// Connects and authenticates to a WebSocket stand-in served over TLS.
+    async fn connect_over_tls(tls: TlsSettings) -> Result<(), BridgeError> {
+        let port = tls_stand_in(|stream| async move {
+            // The error type is given by tungstenite
+            #[allow(clippy::result_large_err)]
+            let check_path = |request: &Request, response| {
+                assert_eq!(request.uri().path(), "/homeassistant/api/websocket");
+                Ok(response)
+            };
+            let mut ws = accept_hdr_async(stream, check_path).await.unwrap();
+            authenticate(&mut ws).await;
+            let _ = ws.next().await;
+        })
+        .await;
+        let connection = connection_settings(
+            websocket_url(&format!("https://127.0.0.1:{port}/homeassistant")).unwrap(),
+            tls.websocket_connector().unwrap(),
+        );
+        connect(&connection).await.map(drop)
+    }
+
+    /// Fetches a page from an HTTPS stand-in with the bridge's HTTP client.
+    async fn get_over_tls(tls: TlsSettings) -> reqwest::Result<String> {
+        let port = tls_stand_in(|mut stream| async move {
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => request.extend_from_slice(&buffer[..n]),
+                }
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = stream.shutdown().await;
+        })
+        .await;
+        tls.http_client()
+            .unwrap()
+            .get(format!("https://127.0.0.1:{port}/"))
+            .send()
+            .await?
+            .text()
+            .await
+    }
+
+    async fn send<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut ServerStream<S>, message: Value) {
+        ws.send(Message::Text(message.to_string())).await.unwrap();
+    }
+
+    /// The next message from the bridge.
+    async fn receive<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut ServerStream<S>) -> Value {
+        loop {
+            if let Message::Text(text) = ws.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut ServerStream<S>) {
+        send(ws, json!({"type": "auth_required"})).await;
+        assert_eq!(receive(ws).await["access_token"], "token");
+        send(ws, json!({"type": "auth_ok"})).await;
+    }
+
+    /// Answers the registry lists and `get_states` with empty lists.
+    async fn answer_snapshot(ws: &mut ServerStream) {
+        for _ in 0..=RegistryKind::ALL.len() {
+            let request = receive(ws).await;
+            send(
+                ws,
+                json!({"id": request["id"], "type": "result", "success": true, "result": []}),
+            )
+            .await;
+        }
+    }
+
+    fn players() -> Players {
+        let art_cache = ArtCache::new(
+            reqwest::Client::new(),
+            "http://127.0.0.1",
+            "token".to_string(),
+            ArtCacheSettings {
+                max_bytes: 0,
+                max_age: Duration::ZERO,
+            },
+        )
+        .unwrap();
+        let (commands, _) = mpsc::channel(1);
+        let (_, health) = watch::channel(ConnectionHealth::default());
+        Players::new(
+            vec![],
+            SettingsByEntity::default(),
+            "http://127.0.0.1".to_string(),
+            Arc::new(art_cache),
+            HAConnection { commands, health },
+        )
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_an_auth_error() {
+        let connection = stand_in(|mut ws| async move {
+            send(&mut ws, json!({"type": "auth_required"})).await;
+            receive(&mut ws).await;
+            send(
+                &mut ws,
+                json!({"type": "auth_invalid", "message": "Invalid access token or password"}),
+            )
+            .await;
+        })
+        .await;
+
+        let error = fetch_media_players(&connection).await.unwrap_err();
+        assert!(
+            matches!(
+                &error,
+                BridgeError::Auth { message, source: TokenSource::Environment }
+                    if message == "Invalid access token or password"
+            ),
+            "{error:?}"
+        );
+        assert!(!error.is_recoverable());
+        assert!(error.to_string().contains("HA_TOKEN"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn non_json_frame_during_auth_is_a_protocol_error() {
+        let connection = stand_in(|mut ws| async move {
+            ws.send(Message::Text("<html>Bad gateway</html>".to_string()))
+                .await
+                .unwrap();
+            let _ = ws.next().await;
+        })
+        .await;
+
+        let error = fetch_media_players(&connection).await.unwrap_err();
+        assert!(matches!(error, BridgeError::Protocol(_)), "{error:?}");
+        assert!(error.is_recoverable());
+    }
+
+    #[tokio::test]
+    async fn unsuccessful_result_fails_the_snapshot() {
+        let connection = stand_in(|mut ws| async move {
+            authenticate(&mut ws).await;
+            loop {
+                let request = receive(&mut ws).await;
+                if request["type"] == "get_states" {
+                    send(
+                        &mut ws,
+                        json!({
+                            "id": request["id"],
+                            "type": "result",
+                            "success": false,
+                            "error": {"code": "unknown_error", "message": "Database is locked"},
+                        }),
+                    )
+                    .await;
+                    break;
+                }
+                send(
+                    &mut ws,
+                    json!({"id": request["id"], "type": "result", "success": true, "result": []}),
+                )
+                .await;
+            }
+            let _ = ws.next().await;
+        })
+        .await;
+
+        let error = fetch_media_players(&connection).await.unwrap_err();
+        assert!(
+            matches!(&error, BridgeError::Protocol(m) if m.contains("get_states") && m.contains("Database is locked")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_registry_only_fails_selectors_that_need_it() {
+        let connection = stand_in(|mut ws| async move {
+            authenticate(&mut ws).await;
+            loop {
+                let request = receive(&mut ws).await;
+                let mut response = match request["type"].as_str().unwrap() {
+                    "config/label_registry/list" => json!({
+                        "success": false,
+                        "error": {"code": "unknown_command", "message": "Unknown command."},
+                    }),
+                    "get_states" => json!({
+                        "success": true,
+                        "result": [
+                            {"entity_id": "media_player.tv", "state": "on", "attributes": {}},
+                            {"entity_id": "light.kitchen", "state": "on", "attributes": {}},
+                        ],
+                    }),
+                    _ => json!({"success": true, "result": []}),
+                };
+                response["id"] = request["id"].clone();
+                response["type"] = json!("result");
+                send(&mut ws, response).await;
+                if request["type"] == "get_states" {
+                    break;
+                }
+            }
+            let _ = ws.next().await;
+        })
+        .await;
+
+        let (registry, players) = fetch_media_players(&connection).await.unwrap();
+        assert_eq!(players.len(), 1);
+        assert_eq!(players[0].entity_id, "media_player.tv");
+
+        let selectors = |selectors: &[&str]| -> Vec<EntitySelector> {
+            selectors
+                .iter()
+                .map(|s| EntitySelector::parse(s).unwrap())
+                .collect()
+        };
+        registry
+            .check_selectors(&selectors(&[
+                "media_player.tv",
+                "media_player.*",
+                "area:kitchen",
+            ]))
+            .unwrap();
+        let error = registry
+            .check_selectors(&selectors(&["media_player.tv", "label:kids"]))
+            .unwrap_err();
+        assert!(
+            matches!(&error, BridgeError::Config(m) if m.contains("label:kids")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_during_snapshot_is_a_transport_error() {
+        let connection = stand_in(|mut ws| async move {
+            authenticate(&mut ws).await;
+            receive(&mut ws).await;
+        })
+        .await;
+
+        let error = fetch_media_players(&connection).await.unwrap_err();
+        assert!(matches!(error, BridgeError::Transport(_)), "{error:?}");
+        assert!(error.is_recoverable());
+    }
+
+    #[tokio::test]
+    async fn listening_skips_non_json_frames_until_the_connection_drops() {
+        let connection = stand_in(|mut ws| async move {
+            authenticate(&mut ws).await;
+            answer_snapshot(&mut ws).await;
+            for _ in 0..=RegistryKind::ALL.len() {
+                assert_eq!(receive(&mut ws).await["type"], "subscribe_events");
+            }
+            ws.send(Message::Text("not json".to_string()))
+                .await
+                .unwrap();
+            send(&mut ws, json!({"type": "event", "event": {}})).await;
+        })
+        .await;
+
+        let mut players = players();
+        let (_commands, mut mpris_rx) = mpsc::channel(1);
+        let (_selection_tx, mut selection) = watch::channel((vec![], SettingsByEntity::default()));
+        let (health, health_rx) = watch::channel(ConnectionHealth::default());
+        let error = listen_for_events(
+            &connection,
+            &mut players,
+            &mut mpris_rx,
+            &mut selection,
+            &health,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, BridgeError::Transport(_)), "{error:?}");
+        assert_eq!(health_rx.borrow().state, ConnectionState::Connected);
+    }
+
+    fn reported(attributes: Value, state: HAState) -> MediaPlayerMetadata {
+        json_to_metadata(
+            serde_json::from_value(attributes).unwrap(),
+            state,
+            "http://homeassistant.local:8123".to_string(),
+        )
+        .unwrap()
+    }
+
+    const REPORTED_AT: &str = "2024-06-01T12:00:00+00:00";
+
+    fn seconds_later(seconds: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(REPORTED_AT)
+            .unwrap()
+            .with_timezone(&Utc)
+            + chrono::TimeDelta::seconds(seconds)
+    }
+
+    #[test]
+    fn position_moves_on_while_playing() {
+        let attributes = json!({
+            "media_duration": 200,
+            "media_position": 30,
+            "media_position_updated_at": REPORTED_AT,
+        });
+        let playing = reported(attributes.clone(), HAState::Playing);
+        assert_eq!(playing.position_at(seconds_later(10)), 40.0);
+        // A clock a little behind HA's does not move it backwards
+        assert_eq!(playing.position_at(seconds_later(-5)), 30.0);
+
+        let paused = reported(attributes, HAState::Paused);
+        assert_eq!(paused.position_at(seconds_later(10)), 30.0);
+    }
+
+    #[test]
+    fn position_stops_at_the_end_of_the_track() {
+        let playing = reported(
+            json!({
+                "media_duration": 200,
+                "media_position": 195,
+                "media_position_updated_at": REPORTED_AT,
+            }),
+            HAState::Playing,
+        );
+        assert_eq!(playing.position_at(seconds_later(60)), 200.0);
+
+        // Streams have no end
+        let stream = reported(
+            json!({"media_position": 195, "media_position_updated_at": REPORTED_AT}),
+            HAState::Playing,
+        );
+        assert_eq!(stream.position_at(seconds_later(60)), 255.0);
+    }
+
+    #[test]
+    fn position_without_an_update_time_is_taken_as_is() {
+        let playing = reported(
+            json!({"media_duration": 200, "media_position": 30}),
+            HAState::Playing,
+        );
+        assert_eq!(playing.position_at(seconds_later(10)), 30.0);
+    }
+
+    #[test]
+    fn text_attributes_are_read_as_they_are() {
+        let metadata = reported(
+            json!({
+                "media_title": "\"Heroes\"",
+                "media_artist": "David Bowie",
+                "repeat": "all",
+            }),
+            HAState::Playing,
+        );
+        assert_eq!(metadata.title, "\"Heroes\"");
+        assert_eq!(metadata.artists, ["David Bowie"]);
+        assert_eq!(metadata.repeat, HALoopStatus::Playlist);
+
+        let odd = reported(json!({"media_title": null, "repeat": 1}), HAState::Playing);
+        assert_eq!(odd.title, "");
+        assert_eq!(odd.repeat, HALoopStatus::None);
+    }
+
+    #[test]
+    fn artists_split_on_unambiguous_separators() {
+        assert_eq!(
+            split_artists("Artist A; Artist B / Artist C feat. Artist D"),
+            ["Artist A", "Artist B", "Artist C", "Artist D"]
+        );
+        assert_eq!(
+            split_artists("Artist A ft. Artist B featuring Artist C"),
+            ["Artist A", "Artist B", "Artist C"]
+        );
+        assert_eq!(split_artists(" ; "), Vec::<String>::new());
+    }
+
+    #[test]
+    fn artist_names_with_commas_stay_whole() {
+        assert_eq!(split_artists("Earth, Wind & Fire"), ["Earth, Wind & Fire"]);
+        assert_eq!(
+            split_artists("Tyler, the Creator feat. Kali Uchis"),
+            ["Tyler, the Creator", "Kali Uchis"]
+        );
+    }
+
+    #[test]
+    fn websocket_url_follows_the_ha_url() {
+        let ws = |ha_url: &str| websocket_url(ha_url).map(|url| url.to_string());
+        assert_eq!(
+            ws("http://ha.local:8123").unwrap(),
+            "ws://ha.local:8123/api/websocket"
+        );
+        assert_eq!(
+            ws("https://ha.example.com").unwrap(),
+            "wss://ha.example.com/api/websocket"
+        );
+        assert_eq!(
+            ws("https://example.com/homeassistant").unwrap(),
+            "wss://example.com/homeassistant/api/websocket"
+        );
+        assert_eq!(
+            ws("https://example.com/homeassistant/").unwrap(),
+            "wss://example.com/homeassistant/api/websocket"
+        );
+        assert_eq!(
+            ws("http://ha.local:8123/?auth_callback=1#onboarding").unwrap(),
+            "ws://ha.local:8123/api/websocket"
+        );
+        assert!(ws("ftp://ha.local").is_err());
+        assert!(ws("ha.local:8123").is_err());
+    }
+
+    #[test]
+    fn art_urls_keep_the_path_prefix() {
+        let art = |art_url: &str, base_url: &str| {
+            validate_art_url(art_url.to_string(), base_url)
+                .unwrap()
+                .to_string()
+        };
+        let picture = "/api/media_player_proxy/media_player.tv?token=abc&cache=1";
+        assert_eq!(
+            art(picture, "https://example.com/homeassistant"),
+            "https://example.com/homeassistant/api/media_player_proxy/media_player.tv?token=abc&cache=1"
+        );
+        assert_eq!(
+            art(picture, "https://example.com/homeassistant/"),
+            "https://example.com/homeassistant/api/media_player_proxy/media_player.tv?token=abc&cache=1"
+        );
+        assert_eq!(
+            art(picture, "http://ha.local:8123"),
+            "http://ha.local:8123/api/media_player_proxy/media_player.tv?token=abc&cache=1"
+        );
+        assert_eq!(
+            art(
+                "//cdn.example.com/cover.jpg",
+                "https://example.com/homeassistant"
+            ),
+            "https://cdn.example.com/cover.jpg"
+        );
+        assert_eq!(
+            art(
+                "https://i.scdn.co/image/ab67",
+                "https://example.com/homeassistant"
+            ),
+            "https://i.scdn.co/image/ab67"
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_rejects_untrusted_certificate_by_default() {
+        let error = connect_over_tls(TlsSettings::default()).await.unwrap_err();
+        assert!(matches!(error, BridgeError::Transport(_)), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn websocket_trusts_ca_bundle() {
+        let tls = TlsSettings {
+            ca_bundle: Some(CA_BUNDLE.into()),
+            accept_invalid_certs: false,
+        };
+        connect_over_tls(tls).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn websocket_accepts_invalid_certs_when_asked() {
+        let tls = TlsSettings {
+            ca_bundle: None,
+            accept_invalid_certs: true,
+        };
+        connect_over_tls(tls).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_rejects_untrusted_certificate_by_default() {
+        assert!(get_over_tls(TlsSettings::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn http_trusts_ca_bundle() {
+        let tls = TlsSettings {
+            ca_bundle: Some(CA_BUNDLE.into()),
+            accept_invalid_certs: false,
+        };
+        assert_eq!(get_over_tls(tls).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn http_accepts_invalid_certs_when_asked() {
+        let tls = TlsSettings {
+            ca_bundle: None,
+            accept_invalid_certs: true,
+        };
+        assert_eq!(get_over_tls(tls).await.unwrap(), "ok");
+    }
+
+    #[test]
+    fn missing_ca_bundle_is_reported() {
+        let tls = TlsSettings {
+            ca_bundle: Some("/nonexistent/ca.pem".into()),
+            accept_invalid_certs: false,
+        };
+        let error = tls.websocket_connector().err().unwrap();
+        assert!(error.to_string().contains("/nonexistent/ca.pem"), "{error}");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_with_jitter() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        for ceiling in [1, 2, 4, 8, 8, 8] {
+            let ceiling = Duration::from_secs(ceiling);
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= ceiling / 2 && delay <= ceiling,
+                "{delay:?} not within {ceiling:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_max_is_at_least_initial() {
+        let mut backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(1));
+        for _ in 0..3 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
         }
-        Err(_e) => Err(eyre::eyre!("Oh no")),
     }
 }
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use secret_service::{EncryptionType, SecretService};
+
+use crate::error::BridgeError;
+
+/// Attribute every item stored by the bridge carries, next to the HA URL.
+const APPLICATION: &str = "ha_mpris_bridge";
+
+fn attributes(ha_url: &str) -> HashMap<&str, &str> {
+    HashMap::from([("application", APPLICATION), ("url", ha_url)])
+}
+
+fn keyring_error(error: secret_service::Error) -> BridgeError {
+    BridgeError::DBus(format!(
+        "Secret Service keyring: {error}. Is a keyring like GNOME Keyring or KWallet running?"
+    ))
+}
+
+/// Looks up the token stored for `ha_url`, unlocking it if needed. Unlocking
+/// may show the keyring's password prompt.
+pub async fn read_token(ha_url: &str) -> Result<String, BridgeError> {
+    let service = SecretService::connect(EncryptionType::Dh)
+        .await
+        .map_err(keyring_error)?;
+    read_token_from(&service, ha_url).await
+}
+
+async fn read_token_from(service: &SecretService<'_>, ha_url: &str) -> Result<String, BridgeError> {
+    let found = service
+        .search_items(attributes(ha_url))
+        .await
+        .map_err(keyring_error)?;
+    let item = match (found.unlocked.first(), found.locked.first()) {
+        (Some(item), _) => item,
+        (None, Some(item)) => {
+            item.unlock().await.map_err(keyring_error)?;
+            item
+        }
+        (None, None) => {
+            return Err(BridgeError::Config(format!(
+                "token_keyring is set, but the Secret Service keyring has no token \
+                 for {ha_url}. Store one with `homeassistant-mpris-bridge-rust store-token`"
+            )))
+        }
+    };
+    let secret = item.get_secret().await.map_err(keyring_error)?;
+    String::from_utf8(secret)
+        .map(|token| token.trim().to_string())
+        .map_err(|_| BridgeError::Config(format!("The stored token for {ha_url} is not text")))
+}
+
+/// Stores `token` for `ha_url` in the default collection, replacing any
+/// token stored for it before.
+pub async fn store_token(ha_url: &str, token: &str) -> Result<(), BridgeError> {
+    let service = SecretService::connect(EncryptionType::Dh)
+        .await
+        .map_err(keyring_error)?;
+    store_token_in(&service, ha_url, token).await
+}
+
+async fn store_token_in(
+    service: &SecretService<'_>,
+    ha_url: &str,
+    token: &str,
+) -> Result<(), BridgeError> {
+    let collection = service
+        .get_default_collection()
+        .await
+        .map_err(keyring_error)?;
+    collection.ensure_unlocked().await.map_err(keyring_error)?;
+    collection
+        .create_item(
+            &format!("Home Assistant token for {ha_url}"),
+            attributes(ha_url),
+            token.as_bytes(),
+            true,
+            "text/plain",
+        )
+        .await
+        .map_err(keyring_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::{Arc, Mutex},
+    };
+
+    use zbus::{
+        interface,
+        zvariant::{ObjectPath, OwnedObjectPath, OwnedValue},
+        ObjectServer,
+    };
+
+    use super::*;
+
+    const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+    const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/login";
+
+    /// `(session, parameters, value, content_type)`, the Secret Service's `Secret` struct.
+    type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+    struct StoredItem {
+        attributes: HashMap<String, String>,
+        secret: Vec<u8>,
+    }
+
+    type Items = Arc<Mutex<Vec<StoredItem>>>;
+
+    fn item_path(index: usize) -> OwnedObjectPath {
+        ObjectPath::try_from(format!("{COLLECTION_PATH}/{index}"))
+            .unwrap()
+            .into()
+    }
+
+    /// Just enough of `org.freedesktop.secrets` for the bridge: plain
+    /// sessions and a single, unlocked default collection.
+    struct Service {
+        items: Items,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Service")]
+    impl Service {
+        fn open_session(
+            &self,
+            algorithm: &str,
+            _input: OwnedValue,
+        ) -> zbus::fdo::Result<(OwnedValue, OwnedObjectPath)> {
+            if algorithm != "plain" {
+                return Err(zbus::fdo::Error::NotSupported(algorithm.to_string()));
+            }
+            let session = ObjectPath::try_from(format!("{SERVICE_PATH}/session/1")).unwrap();
+            Ok((OwnedValue::from(0u8), session.into()))
+        }
+
+        fn search_items(
+            &self,
+            attributes: HashMap<String, String>,
+        ) -> (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) {
+            let items = self.items.lock().unwrap();
+            let unlocked = (0..items.len())
+                .filter(|&i| {
+                    attributes
+                        .iter()
+                        .all(|(k, v)| items[i].attributes.get(k) == Some(v))
+                })
+                .map(item_path)
+                .collect();
+            (unlocked, vec![])
+        }
+
+        fn unlock(&self, objects: Vec<OwnedObjectPath>) -> (Vec<OwnedObjectPath>, OwnedObjectPath) {
+            (objects, ObjectPath::try_from("/").unwrap().into())
+        }
+
+        fn read_alias(&self, name: &str) -> OwnedObjectPath {
+            let path = if name == "default" {
+                COLLECTION_PATH
+            } else {
+                "/"
+            };
+            ObjectPath::try_from(path).unwrap().into()
+        }
+    }
+
+    struct Collection {
+        items: Items,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Collection")]
+    impl Collection {
+        async fn create_item(
+            &self,
+            properties: HashMap<String, OwnedValue>,
+            secret: Secret,
+            replace: bool,
+            #[zbus(object_server)] server: &ObjectServer,
+        ) -> zbus::fdo::Result<(OwnedObjectPath, OwnedObjectPath)> {
+            let attributes: HashMap<String, String> = properties
+                .get("org.freedesktop.Secret.Item.Attributes")
+                .and_then(|value| value.try_clone().ok())
+                .and_then(|value| value.try_into().ok())
+                .unwrap_or_default();
+            let index = {
+                let mut items = self.items.lock().unwrap();
+                let existing = items.iter().position(|item| item.attributes == attributes);
+                match existing.filter(|_| replace) {
+                    Some(index) => {
+                        items[index].secret = secret.2;
+                        index
+                    }
+                    None => {
+                        items.push(StoredItem {
+                            attributes,
+                            secret: secret.2,
+                        });
+                        items.len() - 1
+                    }
+                }
+            };
+            let item = Item {
+                index,
+                items: self.items.clone(),
+            };
+            server.at(item_path(index), item).await?;
+            Ok((item_path(index), ObjectPath::try_from("/").unwrap().into()))
+        }
+
+        #[zbus(property)]
+        fn locked(&self) -> bool {
+            false
+        }
+    }
+
+    struct Item {
+        index: usize,
+        items: Items,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Item")]
+    impl Item {
+        fn get_secret(&self, session: OwnedObjectPath) -> Secret {
+            let secret = self.items.lock().unwrap()[self.index].secret.clone();
+            (session, vec![], secret, "text/plain".to_string())
+        }
+
+        #[zbus(property)]
+        fn locked(&self) -> bool {
+            false
+        }
+    }
+
+    /// A private session bus, stopped when dropped.
+    struct Bus(Child);
+
+    /// The stand-in serves for as long as its connection lives.
+    struct StandIn {
+        _connection: zbus::Connection,
+        _bus: Bus,
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Names the private bus in the environment of the process running
+    /// `round_trip_on_the_stand_in`.
+    const STAND_IN_BUS: &str = "HA_MPRIS_BRIDGE_STAND_IN_BUS";
+
+    /// Starts a private session bus with the stand-in Secret Service, returning
+    /// its address.
+    async fn secret_service_stand_in() -> (StandIn, String) {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("The keyring test needs dbus-daemon to run a private session bus");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let bus = Bus(daemon);
+        let address = address.trim().to_string();
+
+        let items = Items::default();
+        let connection = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .name("org.freedesktop.secrets")
+            .unwrap()
+            .serve_at(
+                SERVICE_PATH,
+                Service {
+                    items: items.clone(),
+                },
+            )
+            .unwrap()
+            .serve_at(COLLECTION_PATH, Collection { items })
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let stand_in = StandIn {
+            _connection: connection,
+            _bus: bus,
+        };
+        (stand_in, address)
+    }
+
+    /// `SecretService` only connects to the bus named by
+    /// DBUS_SESSION_BUS_ADDRESS, which can not be changed safely while other
+    /// tests run. So the round trip runs in a process of its own, started
+    /// with the private bus in its environment.
+    #[tokio::test]
+    async fn tokens_round_trip_through_the_keyring() {
+        let (_stand_in, address) = secret_service_stand_in().await;
+        let output = tokio::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "keyring::tests::round_trip_on_the_stand_in",
+                "--exact",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env("DBUS_SESSION_BUS_ADDRESS", &address)
+            .env(STAND_IN_BUS, &address)
+            .output()
+            .await
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output.status.success() && stdout.contains("1 passed"),
+            "{stdout}{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "run by tokens_round_trip_through_the_keyring, on a private bus"]
+    async fn round_trip_on_the_stand_in() {
+        let stand_in_bus = std::env::var(STAND_IN_BUS).ok();
+        assert!(
+            stand_in_bus.is_some()
+                && stand_in_bus == std::env::var("DBUS_SESSION_BUS_ADDRESS").ok(),
+            "Only runs on the private bus of tokens_round_trip_through_the_keyring, \
+             not to touch the real keyring"
+        );
+        let service = SecretService::connect(EncryptionType::Plain).await.unwrap();
+        let ha_url = "http://ha.local:8123";
+
+        let error = read_token_from(&service, ha_url).await.unwrap_err();
+        assert!(
+            matches!(&error, BridgeError::Config(m) if m.contains("store-token")),
+            "{error:?}"
+        );
+
+        store_token_in(&service, ha_url, "first").await.unwrap();
+        assert_eq!(read_token_from(&service, ha_url).await.unwrap(), "first");
+
+        store_token_in(&service, ha_url, " second\n").await.unwrap();
+        assert_eq!(read_token_from(&service, ha_url).await.unwrap(), "second");
+
+        // Tokens are kept per HA instance
+        store_token_in(&service, "https://ha.example.com", "other")
+            .await
+            .unwrap();
+        assert_eq!(read_token_from(&service, ha_url).await.unwrap(), "second");
+        assert_eq!(
+            read_token_from(&service, "https://ha.example.com")
+                .await
+                .unwrap(),
+            "other"
+        );
+    }
+}
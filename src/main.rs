@@ -1,22 +1,368 @@
-use std::{collections::HashMap, io::Write, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
-use eyre::{OptionExt, Result};
-use homeassistant::{get_media_players, listen_for_events, MediaPlayerState};
-use mpris::new_mpris_player;
+use art::{ArtCache, ArtCacheSettings};
+use clap::Parser;
+use cli::{Cli, Command};
+use discovery::{EntitySelector, PlayerSettings, Players, SettingsByEntity};
+use error::{BridgeError, TOKEN_HELP};
+use eyre::Result;
+use homeassistant::{
+    fetch_media_players, listen_for_events, reject_commands_until, websocket_url, Backoff,
+    ConnectionHealth, ConnectionSettings, ConnectionState, HAConnection, HAState, Heartbeat,
+    TlsSettings, TokenSource,
+};
+use media::{FileShare, OpenUriSettings};
+use mpris::RaiseAction;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::{mpsc, Mutex},
-    task::JoinSet,
+    sync::{mpsc, watch},
+    time::Instant,
 };
 
+mod art;
+mod browse;
+mod cli;
+mod discovery;
+mod error;
 mod homeassistant;
+mod keyring;
+mod media;
 mod mpris;
+mod queue;
+mod reload;
+
+/// Where the config lives, relative to the home directory.
+const CONFIG_FILE: &str = ".config/ha_mpris_bridge/config.toml";
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct Config {
+    /// Overridden by the `HA_URL` environment variable.
+    #[serde(default)]
     home_assistant_url: String,
+    /// Long-lived access token. Only used when none of `HA_TOKEN`,
+    /// `token_command`, `home_assistant_token_file` or `token_keyring` gives one.
+    #[serde(default)]
     home_assistant_token: String,
+    /// File holding the token, e.g. a systemd credential or a sops secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    home_assistant_token_file: Option<PathBuf>,
+    /// Shell command printing the token, e.g. `pass show home-assistant`.
+    /// Takes precedence over `home_assistant_token_file`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_command: Option<String>,
+    /// Read the token from the Secret Service keyring (GNOME Keyring,
+    /// KWallet), where `store-token` puts it.
+    #[serde(default)]
+    token_keyring: bool,
+    /// Which of the above the token was taken from.
+    #[serde(skip)]
+    token_source: TokenSource,
+    /// Media players to bridge: entity ids, globs (`media_player.living_*`),
+    /// `area:<area>`, `label:<label>` or `all`.
     entity_ids: Vec<String>,
+    /// Extra CA certificates (PEM) to trust when talking to HA over https.
+    #[serde(default)]
+    home_assistant_ca_bundle: Option<PathBuf>,
+    /// Accept self-signed or otherwise invalid certificates.
+    #[serde(default)]
+    accept_invalid_certs: bool,
+    /// Size limit of the album art cache, in megabytes.
+    #[serde(default = "default_art_cache_max_size_mb")]
+    art_cache_max_size_mb: u64,
+    /// Album art not shown for this many days is removed from the cache.
+    #[serde(default = "default_art_cache_max_age_days")]
+    art_cache_max_age_days: u64,
+    /// HA states in which players are shown, e.g. to hide them while off.
+    /// Players are always shown when this is not set.
+    #[serde(default)]
+    visible_states: Option<Vec<HAState>>,
+    /// URI schemes players accept through `OpenUri`, e.g. `spotify`.
+    #[serde(default)]
+    supported_uri_schemes: Option<Vec<String>>,
+    /// MIME types advertised to MPRIS clients for `OpenUri`.
+    #[serde(default)]
+    supported_mime_types: Option<Vec<String>>,
+    /// `media_content_type` to send to HA per URI scheme.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    uri_content_types: HashMap<String, String>,
+    /// Local directories served over HTTP, so `file://` URIs can be played.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    file_shares: Vec<FileShare>,
+    /// What `Raise` does: `none`, `dashboard`, `more_info` or `{ command = "..." }`.
+    #[serde(default)]
+    raise: RaiseAction,
+    /// Seconds between heartbeats checking the connection to HA.
+    #[serde(default = "default_heartbeat_interval_secs")]
+    heartbeat_interval_secs: u64,
+    /// Seconds HA gets to answer a heartbeat before reconnecting.
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    heartbeat_timeout_secs: u64,
+    /// Seconds to wait before the first reconnect attempt. The wait doubles
+    /// with every failed attempt, up to `reconnect_max_delay_secs`.
+    #[serde(default = "default_reconnect_min_delay_secs")]
+    reconnect_min_delay_secs: u64,
+    #[serde(default = "default_reconnect_max_delay_secs")]
+    reconnect_max_delay_secs: u64,
+    /// Let `Quit` turn the entity off.
+    #[serde(default)]
+    quit_turns_off: bool,
+    /// Volume and seek changes within this many milliseconds are sent to HA as one.
+    #[serde(default = "default_coalesce_window_ms")]
+    coalesce_window_ms: u64,
+    /// Per entity settings, keyed by entity id.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    entities: HashMap<String, EntityConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct EntityConfig {
+    /// Name shown by desktop shells, instead of the entity's `friendly_name`.
+    #[serde(default)]
+    name: Option<String>,
+    /// Overrides the global `visible_states` for this entity.
+    #[serde(default)]
+    visible_states: Option<Vec<HAState>>,
+    #[serde(default)]
+    supported_uri_schemes: Option<Vec<String>>,
+    #[serde(default)]
+    supported_mime_types: Option<Vec<String>>,
+    /// Added to, and overriding, the global `uri_content_types`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    uri_content_types: HashMap<String, String>,
+    #[serde(default)]
+    raise: Option<RaiseAction>,
+    #[serde(default)]
+    quit_turns_off: Option<bool>,
+}
+
+impl EntityConfig {
+    /// Applies this entity's overrides on top of the global settings.
+    fn apply(&self, default: &PlayerSettings) -> PlayerSettings {
+        let mut settings = default.clone();
+        settings.name = self.name.clone();
+        if let Some(raise) = &self.raise {
+            settings.raise = raise.clone();
+        }
+        if let Some(quit_turns_off) = self.quit_turns_off {
+            settings.quit_turns_off = quit_turns_off;
+        }
+        if let Some(states) = &self.visible_states {
+            settings.visible_states = Some(states.clone());
+        }
+        if let Some(schemes) = &self.supported_uri_schemes {
+            settings.open_uri.schemes = schemes.clone();
+        }
+        if let Some(mime_types) = &self.supported_mime_types {
+            settings.open_uri.mime_types = mime_types.clone();
+        }
+        settings
+            .open_uri
+            .content_types
+            .extend(self.uri_content_types.clone());
+        settings
+    }
+}
+
+impl Config {
+    fn tls_settings(&self) -> TlsSettings {
+        TlsSettings {
+            ca_bundle: self.home_assistant_ca_bundle.clone(),
+            accept_invalid_certs: self.accept_invalid_certs,
+        }
+    }
+
+    fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            interval: Duration::from_secs(self.heartbeat_interval_secs.max(1)),
+            timeout: Duration::from_secs(self.heartbeat_timeout_secs.max(1)),
+        }
+    }
+
+    /// Takes the URL and token from the environment or the configured token
+    /// source, refusing to go on with the placeholders `Config::default()`
+    /// writes to a new config file.
+    async fn resolve_connection_settings(&mut self, env: Env<'_>) -> Result<(), BridgeError> {
+        self.resolve_url(env)?;
+        self.resolve_token(env).await
+    }
+
+    /// `HA_URL` wins over `home_assistant_url`.
+    fn resolve_url(&mut self, env: Env<'_>) -> Result<(), BridgeError> {
+        if let Some(url) = env_var(env, "HA_URL") {
+            self.home_assistant_url = url;
+        }
+        if self.home_assistant_url == Config::default().home_assistant_url
+            || self.home_assistant_url.is_empty()
+        {
+            return Err(BridgeError::Config(
+                "home_assistant_url is not set. Set it to the address of your \
+                 Home Assistant, e.g. \"http://homeassistant.local:8123\", \
+                 or set the HA_URL environment variable"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The first token source set wins: `HA_TOKEN`, `token_command`,
+    /// `home_assistant_token_file`, `token_keyring`, `home_assistant_token`.
+    async fn resolve_token(&mut self, env: Env<'_>) -> Result<(), BridgeError> {
+        self.token_source = TokenSource::ConfigFile;
+        if let Some(token) = env_var(env, "HA_TOKEN") {
+            self.home_assistant_token = token;
+            self.token_source = TokenSource::Environment;
+        } else if let Some(command) = &self.token_command {
+            self.home_assistant_token = run_token_command(command).await?;
+            self.token_source = TokenSource::Command(command.clone());
+        } else if let Some(path) = &self.home_assistant_token_file {
+            let token = std::fs::read_to_string(path).map_err(|e| {
+                BridgeError::Config(format!(
+                    "Could not read home_assistant_token_file {}: {e}",
+                    path.display()
+                ))
+            })?;
+            self.home_assistant_token = token.trim().to_string();
+            self.token_source = TokenSource::File(path.clone());
+        } else if self.token_keyring {
+            self.home_assistant_token = keyring::read_token(&self.home_assistant_url).await?;
+            self.token_source = TokenSource::Keyring;
+        }
+        if self.home_assistant_token == Config::default().home_assistant_token
+            || self.home_assistant_token.is_empty()
+        {
+            return Err(BridgeError::Config(format!(
+                "home_assistant_token is not set. {TOKEN_HELP} \
+                 Paste it as home_assistant_token, or let it come from the \
+                 HA_TOKEN environment variable, token_command, home_assistant_token_file \
+                 or the Secret Service keyring (see `store-token`)."
+            )));
+        }
+        Ok(())
+    }
+
+    fn selectors(&self) -> Result<Vec<EntitySelector>, BridgeError> {
+        if self.entity_ids == Config::default().entity_ids || self.entity_ids.is_empty() {
+            return Err(BridgeError::Config(
+                "entity_ids does not list any media players yet. Add entity ids \
+                 like \"media_player.living_room\", or \"all\" to bridge every player. \
+                 `list-players` shows the available ones"
+                    .to_string(),
+            ));
+        }
+        self.entity_ids
+            .iter()
+            .map(|s| EntitySelector::parse(s))
+            .collect::<Result<Vec<_>>>()
+            .map_err(BridgeError::config)
+    }
+
+    fn save(&self, path: &Path) -> eyre::Result<()> {
+        if let Some(parent_dir) = path.parent() {
+            std::fs::create_dir_all(parent_dir)?;
+        }
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(toml::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    fn connection_settings(&self) -> Result<ConnectionSettings, BridgeError> {
+        Ok(ConnectionSettings {
+            url: websocket_url(&self.home_assistant_url).map_err(BridgeError::config)?,
+            connector: self
+                .tls_settings()
+                .websocket_connector()
+                .map_err(BridgeError::config)?,
+            access_token: self.home_assistant_token.clone(),
+            token_source: self.token_source.clone(),
+            heartbeat: self.heartbeat(),
+        })
+    }
+
+    fn art_cache(&self) -> Result<Arc<ArtCache>, BridgeError> {
+        let cache = ArtCache::new(
+            self.tls_settings()
+                .http_client()
+                .map_err(BridgeError::config)?,
+            &self.home_assistant_url,
+            self.home_assistant_token.clone(),
+            self.art_cache_settings(),
+        )
+        .map_err(BridgeError::config)?;
+        Ok(Arc::new(cache))
+    }
+
+    /// Whether `other` reaches the same HA instance the same way, so bridged
+    /// players can stay when switching to it.
+    fn same_server(&self, other: &Config) -> bool {
+        self.home_assistant_url == other.home_assistant_url
+            && self.home_assistant_ca_bundle == other.home_assistant_ca_bundle
+            && self.accept_invalid_certs == other.accept_invalid_certs
+    }
+
+    /// Whether a connection made with `other` can be kept for this config.
+    fn same_connection(&self, other: &Config) -> bool {
+        self.same_server(other)
+            && self.home_assistant_token == other.home_assistant_token
+            && self.heartbeat_interval_secs == other.heartbeat_interval_secs
+            && self.heartbeat_timeout_secs == other.heartbeat_timeout_secs
+    }
+
+    fn same_backoff(&self, other: &Config) -> bool {
+        self.reconnect_min_delay_secs == other.reconnect_min_delay_secs
+            && self.reconnect_max_delay_secs == other.reconnect_max_delay_secs
+    }
+
+    fn backoff(&self) -> Backoff {
+        Backoff::new(
+            Duration::from_secs(self.reconnect_min_delay_secs.max(1)),
+            Duration::from_secs(self.reconnect_max_delay_secs),
+        )
+    }
+
+    fn art_cache_settings(&self) -> ArtCacheSettings {
+        ArtCacheSettings {
+            max_bytes: self.art_cache_max_size_mb.saturating_mul(1024 * 1024),
+            max_age: Duration::from_secs(self.art_cache_max_age_days.saturating_mul(24 * 60 * 60)),
+        }
+    }
+
+    fn player_settings(&self) -> SettingsByEntity {
+        let mut open_uri = OpenUriSettings::default();
+        if let Some(schemes) = &self.supported_uri_schemes {
+            open_uri.schemes = schemes.clone();
+        }
+        if let Some(mime_types) = &self.supported_mime_types {
+            open_uri.mime_types = mime_types.clone();
+        }
+        open_uri.content_types = self.uri_content_types.clone();
+        open_uri.file_shares = self.file_shares.clone();
+
+        let default = PlayerSettings {
+            visible_states: self.visible_states.clone(),
+            open_uri,
+            name: None,
+            device_name: None,
+            raise: self.raise.clone(),
+            quit_turns_off: self.quit_turns_off,
+            coalesce_window: Duration::from_millis(self.coalesce_window_ms),
+            queue: None,
+        };
+        SettingsByEntity {
+            per_entity: self
+                .entities
+                .iter()
+                .map(|(id, entity)| (id.clone(), entity.apply(&default)))
+                .collect(),
+            default,
+        }
+    }
 }
 
 impl Default for Config {
@@ -24,109 +370,586 @@ impl Default for Config {
         Self {
             home_assistant_url: "YOUR_HA_URL_HERE".to_string(),
             home_assistant_token: "YOUR_HA_TOKEN_HERE".to_string(),
+            home_assistant_token_file: None,
+            token_command: None,
+            token_keyring: false,
+            token_source: TokenSource::ConfigFile,
             entity_ids: vec!["YOUR_MEDIA".to_string(), "PLAYERS_HERE".to_string()],
+            home_assistant_ca_bundle: None,
+            accept_invalid_certs: false,
+            art_cache_max_size_mb: default_art_cache_max_size_mb(),
+            art_cache_max_age_days: default_art_cache_max_age_days(),
+            visible_states: None,
+            supported_uri_schemes: None,
+            supported_mime_types: None,
+            uri_content_types: HashMap::new(),
+            file_shares: vec![],
+            raise: RaiseAction::None,
+            heartbeat_interval_secs: default_heartbeat_interval_secs(),
+            heartbeat_timeout_secs: default_heartbeat_timeout_secs(),
+            reconnect_min_delay_secs: default_reconnect_min_delay_secs(),
+            reconnect_max_delay_secs: default_reconnect_max_delay_secs(),
+            quit_turns_off: false,
+            coalesce_window_ms: default_coalesce_window_ms(),
+            entities: HashMap::new(),
         }
     }
 }
 
+fn default_art_cache_max_size_mb() -> u64 {
+    100
+}
+
+fn default_art_cache_max_age_days() -> u64 {
+    30
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    30
+}
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    10
+}
+
+fn default_reconnect_min_delay_secs() -> u64 {
+    1
+}
+
+fn default_reconnect_max_delay_secs() -> u64 {
+    60
+}
+
+fn default_coalesce_window_ms() -> u64 {
+    200
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
-    let config = get_config()?;
-    let media_players = get_media_players(
-        &config.home_assistant_url,
-        &config.home_assistant_token,
-        config.entity_ids,
-    )
-    .await
-    .unwrap();
-
-    let parsed_url = url::Url::parse(&config.home_assistant_url)?;
-    let websocket_url = format!(
-        "ws://{}{}/api/websocket",
-        parsed_url
-            .host_str()
-            .ok_or_eyre("Can not get host from HA URL")?,
-        match parsed_url.port() {
-            Some(v) => format!(":{}", v),
-            None => "".to_string(),
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let Some(config_file) = cli
+        .config
+        .or_else(|| dirs::home_dir().map(|home| home.join(CONFIG_FILE)))
+    else {
+        let e = BridgeError::Config("Could not find home directory".to_string());
+        eprintln!("{e}");
+        return e.exit_code();
+    };
+    let result = match cli.command.unwrap_or_default() {
+        Command::Run => run(&config_file).await,
+        Command::ListPlayers => cli::list_players(&config_file).await,
+        Command::Check => cli::check(&config_file).await,
+        Command::Init => cli::init(&config_file).await,
+        Command::StoreToken => cli::store_token(&config_file).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            if matches!(e, BridgeError::Config(_) | BridgeError::Auth { .. }) {
+                eprintln!("Config file: {}", config_file.display());
+            }
+            e.exit_code()
+        }
+    }
+}
+
+async fn run(config_file: &Path) -> Result<(), BridgeError> {
+    let mut config = load_config(config_file).await?;
+    let mut connection = config.connection_settings()?;
+    let mut art_cache = config.art_cache()?;
+    let selectors = config.selectors()?;
+    // Every player needs the session bus, so fail early instead of once per player
+    drop(zbus::Connection::session().await?);
+
+    // Channel to handle events from MPRIS to HA
+    let (mpris_tx, mut mpris_rx) = mpsc::channel(100);
+    let (health_tx, health_rx) = watch::channel(ConnectionHealth::default());
+    // Which players to bridge and how, replaced when the config is reloaded
+    let (selection_tx, mut selection_rx) =
+        watch::channel((selectors.clone(), config.player_settings()));
+    let (reload_tx, mut reload_rx) = mpsc::channel(1);
+    reload::watch_config(config_file, reload_tx);
+    let mut reloads = Reloads::new(config_file);
+
+    // MPRIS players are created and removed as matching entities come and go in HA
+    let mut players = Players::new(
+        selectors,
+        config.player_settings(),
+        config.home_assistant_url.clone(),
+        art_cache.clone(),
+        HAConnection {
+            commands: mpris_tx,
+            health: health_rx,
         },
     );
 
-    // Channel to handle events from HA to MPRIS
-    let mut channels = HashMap::new();
+    let mut backoff = config.backoff();
+    loop {
+        health_tx.send_modify(|h| h.state = ConnectionState::Connecting);
+        println!("Connecting to {}", connection.url);
+        // Besides errors, the connection ends for a reloaded config it can not
+        // follow without reconnecting. That hands back the config it used.
+        let outcome = {
+            let listen = listen_for_events(
+                &connection,
+                &mut players,
+                &mut mpris_rx,
+                &mut selection_rx,
+                &health_tx,
+            );
+            tokio::pin!(listen);
+            loop {
+                tokio::select! {
+                    result = &mut listen => break result.map(|()| None),
+                    Some(()) = reload_rx.recv() => reloads.start(&config),
+                    loaded = reloads.next() => {
+                        let Some(previous) = apply_reload(config_file, &mut config, &selection_tx, &mut backoff, loaded) else {
+                            continue;
+                        };
+                        if !config.same_connection(&previous) {
+                            break Ok(Some(previous));
+                        }
+                    }
+                }
+            }
+        };
 
-    // Channel to handle events from MPRIS to HA
-    let (mpris_tx, mpris_rx) = mpsc::channel(100);
-    let mpris_rx = Arc::new(Mutex::new(mpris_rx));
-    let mut set = JoinSet::new();
-
-    let mut media_player_states = HashMap::new();
-
-    for player in &media_players {
-        let (ha_tx, ha_rx) = mpsc::channel(100);
-        channels.insert(player.entity_id.clone(), ha_tx);
-
-        let _mp_task = set.spawn(new_mpris_player(
-            player.entity_id.clone(),
-            player.clone(),
-            config.home_assistant_url.clone(),
-            ha_rx,
-            mpris_tx.clone(),
-        ));
+        let previous = match outcome {
+            Ok(previous) => previous,
+            Err(e) if !e.is_recoverable() => return Err(e),
+            Err(e) => {
+                if health_tx.borrow().state == ConnectionState::Connected {
+                    backoff.reset();
+                }
+                health_tx.send_modify(|h| {
+                    h.state = ConnectionState::Reconnecting;
+                    h.latency = None;
+                });
+                players.mark_unavailable().await;
+                let delay = backoff.next_delay();
+                println!("{e}. Reconnecting in {:.1}s", delay.as_secs_f64());
+                // A reloaded config may fix what went wrong, so it ends the wait
+                let wait = reject_commands_until(&mut mpris_rx, Instant::now() + delay);
+                tokio::pin!(wait);
+                loop {
+                    tokio::select! {
+                        _ = &mut wait => break None,
+                        Some(()) = reload_rx.recv() => reloads.start(&config),
+                        loaded = reloads.next() => {
+                            if let Some(previous) = apply_reload(config_file, &mut config, &selection_tx, &mut backoff, loaded) {
+                                break Some(previous);
+                            }
+                        }
+                    }
+                }
+            }
+        };
 
-        media_player_states.insert(
-            player.entity_id.clone(),
-            MediaPlayerState::new(
-                player.entity_id.clone(),
-                config.home_assistant_url.to_string(),
-                config.home_assistant_token.to_string(),
-            ),
-        );
+        if let Some(previous) = previous.filter(|previous| !config.same_connection(previous)) {
+            println!("Connection settings changed, reconnecting");
+            connection = config.connection_settings()?;
+            if config.same_server(&previous) {
+                art_cache.set_token(config.home_assistant_token.clone());
+            } else {
+                art_cache = config.art_cache()?;
+                players.replace_server(config.home_assistant_url.clone(), art_cache.clone());
+            }
+            backoff = config.backoff();
+        }
+    }
+}
+
+/// A config read again from the file, with the players it selects.
+type Reloaded = Result<(Config, Vec<EntitySelector>), BridgeError>;
+
+/// Reads the config file again on a task of its own, as running a
+/// `token_command` or checking a new token with HA can take a while, and the
+/// connection has to be served meanwhile.
+struct Reloads {
+    config_file: PathBuf,
+    /// Counts the reloads started, so a slow one can not undo a later one.
+    started: u64,
+    loaded_tx: mpsc::Sender<(u64, Reloaded)>,
+    loaded_rx: mpsc::Receiver<(u64, Reloaded)>,
+}
+
+impl Reloads {
+    fn new(config_file: &Path) -> Self {
+        let (loaded_tx, loaded_rx) = mpsc::channel(1);
+        Self {
+            config_file: config_file.to_path_buf(),
+            started: 0,
+            loaded_tx,
+            loaded_rx,
+        }
     }
 
-    println!("Connected to {}", websocket_url);
-    let _ha_task = set.spawn(async move {
+    /// Starts loading the config file to replace `config`.
+    fn start(&mut self, config: &Config) {
+        if !self.config_file.exists() {
+            println!(
+                "{} was removed, keeping the current config",
+                self.config_file.display()
+            );
+            return;
+        }
+        self.started += 1;
+        let reload = self.started;
+        let config_file = self.config_file.clone();
+        let current = config.clone();
+        let loaded = self.loaded_tx.clone();
+        tokio::spawn(async move {
+            let _ = loaded
+                .send((reload, load_reloaded(&config_file, &current).await))
+                .await;
+        });
+    }
+
+    /// The config loaded by the latest reload.
+    async fn next(&mut self) -> Reloaded {
         loop {
-            let websocket_url = websocket_url.clone();
-            let media_player_states = media_player_states.clone();
-            let channels = channels.clone();
-            if let Err(e) = listen_for_events(
-                websocket_url,
-                config.home_assistant_token.to_string(),
-                media_player_states,
-                channels,
-                mpris_rx.clone(),
-            )
-            .await
-            {
-                println!("WebSocket connection lost. {e}. Retrying...");
-                tokio::time::sleep(Duration::from_secs(5)).await;
+            // The sender kept in `self` never lets the channel close
+            if let Some((reload, loaded)) = self.loaded_rx.recv().await {
+                if reload == self.started {
+                    return loaded;
+                }
+            }
+        }
+    }
+}
+
+/// Reads the config file again. An invalid config, or a token HA rejects
+/// when the connection settings changed from `current`, is an error.
+async fn load_reloaded(config_file: &Path, current: &Config) -> Reloaded {
+    let new = load_config(config_file).await?;
+    let selectors = new.selectors()?;
+    let connection = new.connection_settings()?;
+    // An unreachable server may come back, a rejected token will not
+    if !new.same_connection(current) {
+        if let Err(e @ BridgeError::Auth { .. }) = fetch_media_players(&connection).await {
+            return Err(e);
+        }
+    }
+    Ok((new, selectors))
+}
+
+/// Passes the player selection and reconnect delays of a reloaded config on.
+/// A config that did not load is reported and the running config kept.
+/// Returns the config that got replaced.
+fn apply_reload(
+    config_file: &Path,
+    config: &mut Config,
+    selection: &watch::Sender<(Vec<EntitySelector>, SettingsByEntity)>,
+    backoff: &mut Backoff,
+    loaded: Reloaded,
+) -> Option<Config> {
+    match loaded {
+        Ok((new, selectors)) => {
+            println!("Reloaded {}", config_file.display());
+            selection.send_replace((selectors, new.player_settings()));
+            if !new.same_backoff(config) {
+                *backoff = new.backoff();
             }
+            Some(std::mem::replace(config, new))
+        }
+        Err(e) => {
+            eprintln!("Not reloading {}: {e}", config_file.display());
+            None
         }
-    });
+    }
+}
 
-    while (set.join_next().await).is_some() {}
-    Ok(())
+/// Reads the config file and fills in the URL and token from their other
+/// sources, ready to connect.
+async fn load_config(config_file: &Path) -> Result<Config, BridgeError> {
+    let mut config = get_config(config_file).map_err(BridgeError::config)?;
+    config.resolve_connection_settings(&process_env).await?;
+    Ok(config)
+}
+
+/// Looks up environment variables, so tests can pass their own.
+type Env<'a> = &'a (dyn Fn(&str) -> Option<String> + Sync);
+
+fn process_env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
 }
 
-fn get_config() -> eyre::Result<Config> {
-    let home_dir = dirs::home_dir().ok_or_eyre("Could not find home directory")?;
-    let config: PathBuf = home_dir.join(".config/ha_mpris_bridge/config.toml");
+/// An environment variable without surrounding whitespace, unless it is
+/// unset or empty.
+fn env_var(env: Env<'_>, name: &str) -> Option<String> {
+    env(name)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
 
-    if let Some(parent_dir) = config.parent() {
-        std::fs::create_dir_all(parent_dir)?;
+async fn run_token_command(command: &str) -> Result<String, BridgeError> {
+    let failed =
+        |reason: String| BridgeError::Config(format!("token_command `{command}` failed: {reason}"));
+    let output = tokio::process::Command::new("sh")
+        .args(["-c", command])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(failed(match stderr.trim() {
+            "" => output.status.to_string(),
+            stderr => format!("{}: {stderr}", output.status),
+        }));
+    }
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if token.is_empty() {
+        return Err(failed("it printed nothing".to_string()));
     }
+    Ok(token)
+}
 
+fn get_config(config: &Path) -> eyre::Result<Config> {
     if !config.exists() {
-        let default_config = Config::default();
-        let toml_content = toml::to_string_pretty(&default_config)?;
-
-        let mut file = std::fs::File::create(&config)?;
-        file.write_all(toml_content.as_bytes())?;
+        Config::default().save(config)?;
+        println!("Created {}", config.display());
     }
 
-    let config = std::fs::read_to_string(&config)?;
+    let config = std::fs::read_to_string(config)?;
 
     let config: Config = toml::from_str(&config)?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            home_assistant_url: "http://ha.local:8123".to_string(),
+            home_assistant_token: "from_config".to_string(),
+            ..Config::default()
+        }
+    }
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    fn token_file(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    async fn token(mut config: Config, vars: &[(&str, &str)]) -> Result<String, BridgeError> {
+        config.resolve_token(&env(vars)).await?;
+        Ok(config.home_assistant_token)
+    }
+
+    fn reloadable(token_command: &str) -> Config {
+        Config {
+            // Refuses connections, which a reload tolerates
+            home_assistant_url: "http://127.0.0.1:1".to_string(),
+            token_command: Some(token_command.to_string()),
+            entity_ids: vec!["media_player.kitchen".to_string()],
+            ..config()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_slow_reload_does_not_undo_a_later_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+        let mut reloads = Reloads::new(&config_file);
+
+        reloadable("sleep 1; echo slow").save(&config_file).unwrap();
+        reloads.start(&config());
+        // Give the slow reload time to read the file before it changes
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        reloadable("echo fast").save(&config_file).unwrap();
+        reloads.start(&config());
+
+        let (reloaded, _) = reloads.next().await.unwrap();
+        assert_eq!(reloaded.home_assistant_token, "fast");
+        let late = tokio::time::timeout(Duration::from_secs(2), reloads.next()).await;
+        assert!(late.is_err(), "the slow reload was applied after all");
+    }
+
+    #[test]
+    fn a_reload_applies_new_reconnect_delays() {
+        let mut current = config();
+        let mut backoff = current.backoff();
+        let (selection, _) = watch::channel(Default::default());
+        let new = Config {
+            reconnect_min_delay_secs: 100,
+            reconnect_max_delay_secs: 100,
+            ..config()
+        };
+
+        apply_reload(
+            Path::new("config.toml"),
+            &mut current,
+            &selection,
+            &mut backoff,
+            Ok((new, vec![])),
+        );
+        assert!(backoff.next_delay() >= Duration::from_secs(50));
+    }
+
+    #[test]
+    fn huge_art_cache_limits_saturate() {
+        let settings = Config {
+            art_cache_max_size_mb: u64::MAX,
+            art_cache_max_age_days: u64::MAX,
+            ..config()
+        }
+        .art_cache_settings();
+        assert_eq!(settings.max_bytes, u64::MAX);
+        assert_eq!(settings.max_age, Duration::from_secs(u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn plaintext_token_is_the_last_resort() {
+        assert_eq!(token(config(), &[]).await.unwrap(), "from_config");
+    }
+
+    #[tokio::test]
+    async fn token_file_wins_over_keyring_and_plaintext() {
+        let file = token_file("  from_file\n");
+        let config = Config {
+            home_assistant_token_file: Some(file.path().to_path_buf()),
+            token_keyring: true,
+            ..config()
+        };
+        assert_eq!(token(config, &[]).await.unwrap(), "from_file");
+    }
+
+    #[tokio::test]
+    async fn token_command_wins_over_token_file() {
+        let file = token_file("from_file");
+        let config = Config {
+            token_command: Some("echo ' from_command '".to_string()),
+            home_assistant_token_file: Some(file.path().to_path_buf()),
+            token_keyring: true,
+            ..config()
+        };
+        assert_eq!(token(config, &[]).await.unwrap(), "from_command");
+    }
+
+    #[tokio::test]
+    async fn ha_token_wins_over_everything() {
+        let file = token_file("from_file");
+        let config = Config {
+            token_command: Some("echo from_command".to_string()),
+            home_assistant_token_file: Some(file.path().to_path_buf()),
+            token_keyring: true,
+            ..config()
+        };
+        let vars = [("HA_TOKEN", " from_env\n")];
+        assert_eq!(token(config, &vars).await.unwrap(), "from_env");
+    }
+
+    #[tokio::test]
+    async fn token_source_is_recorded() {
+        let file = token_file("from_file");
+        let mut config = Config {
+            home_assistant_token_file: Some(file.path().to_path_buf()),
+            ..config()
+        };
+        config.resolve_token(&env(&[])).await.unwrap();
+        assert_eq!(
+            config.token_source,
+            TokenSource::File(file.path().to_path_buf())
+        );
+        config
+            .resolve_token(&env(&[("HA_TOKEN", "from_env")]))
+            .await
+            .unwrap();
+        assert_eq!(config.token_source, TokenSource::Environment);
+        assert_eq!(
+            config.connection_settings().unwrap().token_source,
+            TokenSource::Environment
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_environment_variables_are_ignored() {
+        let vars = [("HA_TOKEN", ""), ("HA_URL", " ")];
+        assert_eq!(token(config(), &vars).await.unwrap(), "from_config");
+        let mut config = config();
+        config.resolve_url(&env(&vars)).unwrap();
+        assert_eq!(config.home_assistant_url, "http://ha.local:8123");
+    }
+
+    #[test]
+    fn ha_url_wins_over_config() {
+        let mut config = config();
+        config
+            .resolve_url(&env(&[("HA_URL", "https://ha.example.com")]))
+            .unwrap();
+        assert_eq!(config.home_assistant_url, "https://ha.example.com");
+    }
+
+    #[tokio::test]
+    async fn failing_token_command_is_reported() {
+        let config = Config {
+            token_command: Some("echo 'vault is sealed' >&2; exit 3".to_string()),
+            ..config()
+        };
+        let error = token(config, &[]).await.unwrap_err();
+        assert!(
+            matches!(&error, BridgeError::Config(m) if m.contains("vault is sealed")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_token_command_output_is_an_error() {
+        let config = Config {
+            token_command: Some("true".to_string()),
+            ..config()
+        };
+        let error = token(config, &[]).await.unwrap_err();
+        assert!(
+            matches!(&error, BridgeError::Config(m) if m.contains("printed nothing")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_token_file_is_reported() {
+        let config = Config {
+            home_assistant_token_file: Some("/nonexistent/ha_token".into()),
+            ..config()
+        };
+        let error = token(config, &[]).await.unwrap_err();
+        assert!(
+            matches!(&error, BridgeError::Config(m) if m.contains("/nonexistent/ha_token")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn placeholders_are_rejected() {
+        let placeholders = Config::default();
+        assert!(matches!(
+            placeholders.clone().resolve_url(&env(&[])),
+            Err(BridgeError::Config(_))
+        ));
+        let config = Config {
+            home_assistant_token: placeholders.home_assistant_token,
+            ..config()
+        };
+        assert!(matches!(
+            token(config, &[]).await,
+            Err(BridgeError::Config(_))
+        ));
+    }
+}
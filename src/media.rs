@@ -0,0 +1,260 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+pub const DEFAULT_URI_SCHEMES: [&str; 3] = ["http", "https", "media-source"];
+
+pub const DEFAULT_MIME_TYPES: [&str; 12] = [
+    "audio/mpeg",
+    "audio/aac",
+    "audio/mp4",
+    "audio/ogg",
+    "audio/opus",
+    "audio/flac",
+    "audio/x-flac",
+    "audio/wav",
+    "audio/x-wav",
+    "audio/x-mpegurl",
+    "application/vnd.apple.mpegurl",
+    "video/mp4",
+];
+
+/// A local directory that HA players can reach over HTTP, e.g. through a NAS
+/// or a web server, so `file://` URIs below it can be played.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileShare {
+    pub path: PathBuf,
+    pub url: String,
+}
+
+/// How `OpenUri` requests are turned into `media_player.play_media` calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenUriSettings {
+    pub schemes: Vec<String>,
+    pub mime_types: Vec<String>,
+    /// `media_content_type` to use per URI scheme, e.g. `spotify = "music"`.
+    /// Schemes not listed here get a type based on the file extension.
+    pub content_types: HashMap<String, String>,
+    pub file_shares: Vec<FileShare>,
+}
+
+impl Default for OpenUriSettings {
+    fn default() -> Self {
+        Self {
+            schemes: DEFAULT_URI_SCHEMES.map(String::from).to_vec(),
+            mime_types: DEFAULT_MIME_TYPES.map(String::from).to_vec(),
+            content_types: HashMap::new(),
+            file_shares: vec![],
+        }
+    }
+}
+
+/// What to pass to `play_media`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MediaRequest {
+    pub content_id: String,
+    pub content_type: String,
+}
+
+#[derive(Debug)]
+pub enum OpenUriError {
+    InvalidUri(String),
+    UnsupportedScheme(String),
+    /// A `file://` URI outside of every configured share.
+    NotShared(PathBuf),
+}
+
+impl std::fmt::Display for OpenUriError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUri(uri) => write!(f, "{uri} is not a valid URI"),
+            Self::UnsupportedScheme(scheme) => {
+                write!(f, "URIs starting with {scheme}: are not supported")
+            }
+            Self::NotShared(path) => write!(
+                f,
+                "{} is not in any of the configured file shares",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl OpenUriSettings {
+    pub fn resolve(&self, uri: &str) -> Result<MediaRequest, OpenUriError> {
+        // `spotify:track:...` style URIs are not URLs, so only the scheme is looked at
+        let scheme = uri
+            .split_once(':')
+            .map(|(scheme, _)| scheme.to_ascii_lowercase())
+            .ok_or_else(|| OpenUriError::InvalidUri(uri.to_string()))?;
+        if !self.schemes.contains(&scheme) {
+            return Err(OpenUriError::UnsupportedScheme(scheme));
+        }
+
+        let content_id = if scheme == "file" {
+            self.shared_url(uri)?
+        } else {
+            uri.to_string()
+        };
+        let content_type = self
+            .content_types
+            .get(&scheme)
+            .cloned()
+            .unwrap_or_else(|| content_type_for(&content_id).to_string());
+        Ok(MediaRequest {
+            content_id,
+            content_type,
+        })
+    }
+
+    /// Maps a local file onto the URL it is served at.
+    fn shared_url(&self, uri: &str) -> Result<String, OpenUriError> {
+        let path = Url::parse(uri)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| OpenUriError::InvalidUri(uri.to_string()))?;
+        self.file_shares
+            .iter()
+            .find_map(|share| {
+                let relative = path.strip_prefix(&share.path).ok()?;
+                let mut url = Url::parse(&share.url).ok()?;
+                url.path_segments_mut()
+                    .ok()?
+                    .pop_if_empty()
+                    .extend(relative.iter().filter_map(|part| part.to_str()));
+                Some(url.to_string())
+            })
+            .ok_or(OpenUriError::NotShared(path))
+    }
+}
+
+/// Guesses the HA `media_content_type` from the file extension.
+fn content_type_for(content_id: &str) -> &'static str {
+    let path = content_id.split(['?', '#']).next().unwrap_or_default();
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "mkv" | "webm" | "mov" | "avi" => "video",
+        "jpg" | "jpeg" | "png" | "gif" | "webp" => "image",
+        "m3u" | "m3u8" | "pls" => "playlist",
+        _ => "music",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_file_share() -> OpenUriSettings {
+        let mut settings = OpenUriSettings::default();
+        settings.schemes.push("file".to_string());
+        settings.file_shares.push(FileShare {
+            path: PathBuf::from("/srv/music"),
+            url: "http://nas.local/music/".to_string(),
+        });
+        settings
+    }
+
+    fn content_id(settings: &OpenUriSettings, uri: &str) -> String {
+        settings.resolve(uri).unwrap().content_id
+    }
+
+    #[test]
+    fn only_allowed_schemes_are_played() {
+        let settings = OpenUriSettings::default();
+        assert!(settings.resolve("https://radio.example/stream").is_ok());
+        assert!(settings.resolve("HTTP://radio.example/stream").is_ok());
+        assert!(matches!(
+            settings.resolve("spotify:track:6rqhFgbbKwnb9MLmUQDhG6"),
+            Err(OpenUriError::UnsupportedScheme(scheme)) if scheme == "spotify"
+        ));
+        assert!(matches!(
+            settings.resolve("file:///srv/music/song.mp3"),
+            Err(OpenUriError::UnsupportedScheme(_))
+        ));
+        assert!(matches!(
+            settings.resolve("not a uri"),
+            Err(OpenUriError::InvalidUri(_))
+        ));
+    }
+
+    #[test]
+    fn configured_schemes_use_their_content_type() {
+        let mut settings = OpenUriSettings::default();
+        settings.schemes.push("spotify".to_string());
+        settings
+            .content_types
+            .insert("spotify".to_string(), "music".to_string());
+        assert_eq!(
+            settings
+                .resolve("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M")
+                .unwrap(),
+            MediaRequest {
+                content_id: "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M".to_string(),
+                content_type: "music".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn content_types_are_guessed_from_the_extension() {
+        assert_eq!(content_type_for("http://nas.local/song.MP3"), "music");
+        assert_eq!(content_type_for("http://nas.local/clip.mp4?t=10"), "video");
+        assert_eq!(content_type_for("http://nas.local/cover.jpg#x"), "image");
+        assert_eq!(
+            content_type_for("http://radio.example/live.m3u8"),
+            "playlist"
+        );
+        assert_eq!(content_type_for("https://radio.example/stream"), "music");
+    }
+
+    #[test]
+    fn shared_files_are_played_from_their_url() {
+        let settings = with_file_share();
+        assert_eq!(
+            settings.resolve("file:///srv/music/Album/01.flac").unwrap(),
+            MediaRequest {
+                content_id: "http://nas.local/music/Album/01.flac".to_string(),
+                content_type: "music".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn shared_file_urls_are_percent_encoded() {
+        let settings = with_file_share();
+        let expected = "http://nas.local/music/Some%20Artist/My%20Song.flac";
+        assert_eq!(
+            content_id(&settings, "file:///srv/music/Some%20Artist/My%20Song.flac"),
+            expected
+        );
+        assert_eq!(
+            content_id(&settings, "file:///srv/music/Some Artist/My Song.flac"),
+            expected
+        );
+    }
+
+    #[test]
+    fn share_urls_work_without_a_trailing_slash() {
+        let mut settings = with_file_share();
+        settings.file_shares[0].url = "http://nas.local/music".to_string();
+        assert_eq!(
+            content_id(&settings, "file:///srv/music/01.flac"),
+            "http://nas.local/music/01.flac"
+        );
+    }
+
+    #[test]
+    fn files_outside_the_shares_are_rejected() {
+        let settings = with_file_share();
+        for uri in ["file:///home/me/song.mp3", "file:///srv/music2/song.mp3"] {
+            assert!(
+                matches!(settings.resolve(uri), Err(OpenUriError::NotShared(_))),
+                "{uri}"
+            );
+        }
+    }
+}
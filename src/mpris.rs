@@ -1,36 +1,567 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
 
+use chrono::{DateTime, Utc};
 use mpris_server::{
-    zbus::fdo, LoopStatus, Metadata, PlaybackRate, PlaybackStatus, PlayerInterface, Property,
-    RootInterface, Server, Time, TrackId, Volume,
+    zbus::fdo, LoopStatus, Metadata, PlaybackRate, PlaybackStatus, PlayerInterface, Playlist,
+    PlaylistId, PlaylistOrdering, PlaylistsInterface, PlaylistsProperty, Property, RootInterface,
+    Server, Signal, Time, TrackId, TrackListInterface, TrackListProperty, TrackListSignal, Uri,
+    Volume,
 };
-use tokio::sync::{
-    mpsc::{Receiver, Sender},
-    Mutex,
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    process::Command,
+    sync::{
+        mpsc::{Receiver, Sender},
+        oneshot, watch, Mutex, Notify,
+    },
+    task::JoinSet,
+    time::Instant,
 };
 
-use crate::homeassistant::{
-    json_to_metadata, HAEvent, HALoopStatus, MediaPlayer, MediaPlayerMetadata,
+use crate::{
+    art::ArtCache,
+    browse::{self, BrowseItem},
+    discovery::PlayerSettings,
+    homeassistant::{
+        json_to_metadata, validate_art_url, CommandReply, ConnectionHealth, HAConnection, HAEvent,
+        HALoopStatus, HAState, MediaPlayer, MediaPlayerMetadata, ServiceCallError,
+        SupportedFeatures,
+    },
+    media::{MediaRequest, OpenUriError},
+    queue::{Enqueue, Queue, QueueItem, QueueSource},
 };
 
+/// How often to ask for a bus name that is still taken.
+const BUS_NAME_ATTEMPTS: u32 = 20;
+
+/// How long an MPRIS caller waits for HA to acknowledge a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an optimistic change is shown without HA reporting it, before
+/// it is rolled back.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Name of the shipped `.desktop` file, without its extension.
+const DESKTOP_ENTRY: &str = "homeassistant-mpris-bridge-rust";
+
+/// Longest bus name HA entity ids are mapped to; D-Bus allows 255 characters.
+const MAX_BUS_NAME_LENGTH: usize = 200;
+
+/// Where mpris-server serves its interfaces.
+const MPRIS_OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// Position jumps smaller than this (in seconds) are not reported as `Seeked`.
+const SEEK_TOLERANCE: f64 = 1.0;
+
+/// Most `volume_up`/`volume_down` steps sent to reach one volume, for
+/// players that never quite get there.
+const MAX_VOLUME_STEPS: u32 = 50;
+
+/// Volumes closer than this count as the same, as players round the volume
+/// to their own steps.
+const VOLUME_TOLERANCE: f64 = 0.01;
+
+/// What `Raise` does. Without an action players can not be raised.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RaiseAction {
+    #[default]
+    None,
+    /// Opens the HA frontend in the default browser.
+    Dashboard,
+    /// Opens the HA frontend with the entity's more-info dialog.
+    MoreInfo,
+    /// Runs a shell command, with `HA_ENTITY_ID` and `HA_URL` set.
+    Command(String),
+}
+
+impl RaiseAction {
+    fn command(&self, entity_id: &str, base_url: &str) -> Option<Command> {
+        let mut command = match self {
+            Self::None => return None,
+            Self::Dashboard => {
+                let mut command = Command::new("xdg-open");
+                command.arg(base_url);
+                command
+            }
+            Self::MoreInfo => {
+                let mut url = url::Url::parse(base_url).ok()?;
+                url.query_pairs_mut()
+                    .append_pair("more-info-entity-id", entity_id);
+                let mut command = Command::new("xdg-open");
+                command.arg(url.as_str());
+                command
+            }
+            Self::Command(shell_command) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(shell_command);
+                command
+            }
+        };
+        command
+            .env("HA_ENTITY_ID", entity_id)
+            .env("HA_URL", base_url);
+        Some(command)
+    }
+}
+
 #[derive(Clone)]
 pub struct MyPlayer {
     entity_id: String,
-    ha_sender: tokio::sync::mpsc::Sender<(String, HAEvent)>,
+    base_url: String,
+    ha_sender: Sender<(String, HAEvent, CommandReply)>,
+    health: watch::Receiver<ConnectionHealth>,
     metadata: Arc<Mutex<MediaPlayerMetadata>>,
+    settings: PlayerSettings,
+    queue: Arc<Mutex<Queue>>,
+    /// Asks for the queue to be fetched again, e.g. after adding a track.
+    queue_changed: Arc<Notify>,
+    playlists: Arc<Mutex<Vec<BrowsedPlaylist>>>,
+    /// Asks for the playlists to be browsed again, e.g. once their icons are
+    /// downloaded.
+    playlists_changed: Arc<Notify>,
+    /// Latest volume and seek targets not sent to HA yet.
+    coalesced: Arc<std::sync::Mutex<HashMap<Coalesced, f64>>>,
+    /// Changes already shown to clients that HA has yet to confirm.
+    expected: Arc<std::sync::Mutex<Vec<Expectation>>>,
+    /// Asks the player task to show a new set of expected changes.
+    expected_changed: Arc<Notify>,
+    /// Wakes whoever waits for HA to report a change, e.g. a volume step.
+    metadata_changed: Arc<Notify>,
+}
+
+/// A change shown to MPRIS clients before HA reported it, so widgets react
+/// to a button press right away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Expected {
+    State(HAState),
+    Volume(f64),
+    Muted(bool),
+    Shuffle(bool),
+    Repeat(HALoopStatus),
+}
+
+impl Expected {
+    fn apply(&self, metadata: &mut MediaPlayerMetadata) {
+        match *self {
+            Self::State(state) => {
+                // Keep the position where it is instead of extrapolating from HA's last report
+                metadata.position = metadata.current_position();
+                metadata.position_updated_at = Some(chrono::Utc::now());
+                metadata.state = state;
+            }
+            Self::Volume(volume) => metadata.volume = volume,
+            Self::Muted(muted) => metadata.muted = muted,
+            Self::Shuffle(shuffle) => metadata.shuffle = shuffle,
+            Self::Repeat(repeat) => metadata.repeat = repeat,
+        }
+    }
+
+    fn is_confirmed_by(&self, metadata: &MediaPlayerMetadata) -> bool {
+        match *self {
+            // e.g. `buffering` confirms a play just as well as `playing`
+            Self::State(state) => playback_status(state) == playback_status(metadata.state),
+            Self::Volume(volume) => (metadata.volume - volume).abs() < VOLUME_TOLERANCE,
+            Self::Muted(muted) => metadata.muted == muted,
+            Self::Shuffle(shuffle) => metadata.shuffle == shuffle,
+            Self::Repeat(repeat) => metadata.repeat == repeat,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Expectation {
+    change: Expected,
+    deadline: Instant,
+}
+
+/// Commands that clients fire in bursts, e.g. while dragging a slider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Coalesced {
+    Volume,
+    Seek,
+}
+
+/// The services that take an entity to a new volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct VolumeChange {
+    mute: Option<bool>,
+    level: Option<VolumeLevel>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VolumeLevel {
+    Set(Volume),
+    /// For players that can only step the volume up and down.
+    StepTowards(Volume),
+}
+
+/// Maps an MPRIS volume onto HA's services. A volume of 0 mutes players that
+/// can mute, and muting keeps HA's volume level, so unmuting brings the old
+/// level back. Returns nothing for entities without any volume control.
+fn volume_change(volume: Volume, features: SupportedFeatures, muted: bool) -> Option<VolumeChange> {
+    let can_mute = features.contains(SupportedFeatures::VOLUME_MUTE);
+    if volume <= 0.0 && can_mute {
+        return Some(VolumeChange {
+            mute: (!muted).then_some(true),
+            level: None,
+        });
+    }
+    let level = if features.contains(SupportedFeatures::VOLUME_SET) {
+        Some(VolumeLevel::Set(volume))
+    } else if features.contains(SupportedFeatures::VOLUME_STEP) {
+        Some(VolumeLevel::StepTowards(volume))
+    } else if can_mute {
+        None
+    } else {
+        return None;
+    };
+    Some(VolumeChange {
+        mute: (muted && can_mute).then_some(false),
+        level,
+    })
+}
+
+/// Where a `Seek` or `SetPosition` call takes the player.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SeekTarget {
+    /// Position in seconds.
+    Position(f64),
+    /// Seeking past the end of the track acts like Next, as per the MPRIS spec.
+    Next,
+    /// Stale track ids and out of range positions are ignored, as per the MPRIS spec.
+    Ignored,
+}
+
+/// Seeks by `offset` from `position`, in a track of `duration` seconds (0 if unknown).
+fn relative_seek(position: f64, offset: Time, duration: f64) -> SeekTarget {
+    let target = position + to_secs(offset);
+    if duration > 0.0 && target > duration {
+        SeekTarget::Next
+    } else {
+        SeekTarget::Position(target.max(0.0))
+    }
+}
+
+/// Seeks to `position` of the track `track_id`, if that is still playing.
+fn absolute_seek(track_id: &TrackId, position: Time, metadata: &MediaPlayerMetadata) -> SeekTarget {
+    if *track_id != track_id_for(metadata)
+        || position.is_negative()
+        || (metadata.duration > 0.0 && to_secs(position) > metadata.duration)
+    {
+        SeekTarget::Ignored
+    } else {
+        SeekTarget::Position(to_secs(position))
+    }
+}
+
+/// Whether the position jumped between two reports of the same track, rather
+/// than moved on with playback. A new track starting over is not a seek,
+/// neither is pausing or resuming.
+fn is_seek(
+    previous: &MediaPlayerMetadata,
+    update: &MediaPlayerMetadata,
+    now: DateTime<Utc>,
+) -> bool {
+    track_id_for(previous) == track_id_for(update)
+        && (update.position_at(now) - previous.position_at(now)).abs() > SEEK_TOLERANCE
+}
+
+/// A playable entry of the media browser, published as an MPRIS playlist.
+#[derive(Debug, Clone, PartialEq)]
+struct BrowsedPlaylist {
+    playlist: Playlist,
+    media: MediaRequest,
+}
+
+impl MyPlayer {
+    async fn supports(&self, feature: SupportedFeatures) -> bool {
+        self.metadata.lock().await.features.contains(feature)
+    }
+
+    /// Rejects actions the entity does not advertise instead of forwarding them to HA.
+    async fn require(&self, feature: SupportedFeatures, action: &str) -> fdo::Result<()> {
+        if self.supports(feature).await {
+            Ok(())
+        } else {
+            Err(fdo::Error::NotSupported(format!(
+                "{} does not support {action}",
+                self.entity_id
+            )))
+        }
+    }
+
+    /// Forwards a command to HA and waits for HA's verdict on it.
+    async fn send_command(&self, event: HAEvent) -> fdo::Result<()> {
+        self.call(event).await.map(drop)
+    }
+
+    /// Like `send_command`, returning what HA answered with.
+    async fn call(&self, event: HAEvent) -> fdo::Result<Value> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.ha_sender
+            .send((self.entity_id.clone(), event, reply_tx))
+            .await
+            .map_err(|_| fdo::Error::Failed("The Home Assistant connection is gone".into()))?;
+
+        match tokio::time::timeout(COMMAND_TIMEOUT, reply_rx).await {
+            Ok(Ok(result)) => result.map_err(Into::into),
+            Ok(Err(_)) => Err(fdo::Error::Failed(
+                "Connection to Home Assistant was lost before the command completed".into(),
+            )),
+            Err(_) => Err(fdo::Error::TimedOut(
+                "Home Assistant did not answer in time".into(),
+            )),
+        }
+    }
+}
+
+impl MyPlayer {
+    /// Shows `change` right away, then sends `event` to HA. The change is
+    /// rolled back if HA rejects the command.
+    async fn send_optimistic(&self, event: HAEvent, change: Expected) -> fdo::Result<()> {
+        let expectation = Expectation {
+            change,
+            deadline: Instant::now() + COMMAND_TIMEOUT + CONFIRM_TIMEOUT,
+        };
+        self.expected.lock().unwrap().push(expectation);
+        self.expected_changed.notify_one();
+
+        let result = self.send_command(event).await;
+        let mut expected = self.expected.lock().unwrap();
+        match &result {
+            Ok(()) => {
+                // HA accepted it, now it has to show up in the state
+                if let Some(e) = expected.iter_mut().find(|e| e.change == change) {
+                    e.deadline = Instant::now() + CONFIRM_TIMEOUT;
+                }
+            }
+            Err(e) => {
+                println!(
+                    "Rolling back {change:?} on {}, as HA rejected it: {e}",
+                    self.entity_id
+                );
+                expected.retain(|e| e.change != change);
+            }
+        }
+        self.expected_changed.notify_one();
+        result
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.expected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.deadline)
+            .min()
+    }
+
+    /// HA's state with the expected changes still pending applied on top.
+    /// Changes HA confirmed or did not report in time are dropped.
+    fn reconcile(&self, confirmed: &MediaPlayerMetadata) -> MediaPlayerMetadata {
+        let now = Instant::now();
+        let mut expected = self.expected.lock().unwrap();
+        expected.retain(|e| {
+            if e.change.is_confirmed_by(confirmed) {
+                return false;
+            }
+            if e.deadline <= now {
+                println!(
+                    "Rolling back {:?} on {}, as HA did not confirm it in time",
+                    e.change, self.entity_id
+                );
+                return false;
+            }
+            true
+        });
+        let mut metadata = confirmed.clone();
+        for e in expected.iter() {
+            e.change.apply(&mut metadata);
+        }
+        metadata
+    }
+
+    fn pending(&self, kind: Coalesced) -> Option<f64> {
+        self.coalesced.lock().unwrap().get(&kind).copied()
+    }
+
+    /// Sends only the latest target set within the coalescing window. The
+    /// caller does not wait for HA, so a burst of volume changes can not hold
+    /// up e.g. a pause behind it.
+    fn coalesce(&self, kind: Coalesced, target: f64) {
+        let scheduled = self
+            .coalesced
+            .lock()
+            .unwrap()
+            .insert(kind, target)
+            .is_some();
+        if scheduled {
+            return;
+        }
+        let player = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(player.settings.coalesce_window).await;
+            let Some(target) = player.coalesced.lock().unwrap().remove(&kind) else {
+                return;
+            };
+            let result = match kind {
+                Coalesced::Volume => player.apply_volume(target).await,
+                Coalesced::Seek => player.send_command(HAEvent::Seek(target)).await,
+            };
+            if let Err(e) = result {
+                println!("Could not send {kind:?} to {}: {e}", player.entity_id);
+            }
+        });
+    }
+
+    /// Sends the HA services for a new volume, depending on what the entity supports.
+    async fn apply_volume(&self, volume: Volume) -> fdo::Result<()> {
+        let (features, muted) = {
+            let metadata = self.metadata.lock().await;
+            (metadata.features, metadata.muted)
+        };
+        let change = volume_change(volume, features, muted).ok_or_else(|| {
+            fdo::Error::NotSupported(format!(
+                "{} does not support setting the volume",
+                self.entity_id
+            ))
+        })?;
+        if let Some(mute) = change.mute {
+            self.send_optimistic(HAEvent::Mute(mute), Expected::Muted(mute))
+                .await?;
+        }
+        match change.level {
+            Some(VolumeLevel::Set(volume)) => {
+                self.send_optimistic(HAEvent::Volume(volume), Expected::Volume(volume))
+                    .await
+            }
+            Some(VolumeLevel::StepTowards(volume)) => self.step_volume(volume).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Steps the volume until HA reports it reached or passed `target`. The
+    /// step size is up to the player, so it may end up a little past it.
+    async fn step_volume(&self, target: Volume) -> fdo::Result<()> {
+        let start = self.metadata.lock().await.volume;
+        let up = if target > start + VOLUME_TOLERANCE {
+            true
+        } else if target < start - VOLUME_TOLERANCE {
+            false
+        } else {
+            return Ok(());
+        };
+        let mut volume = start;
+        for _ in 0..MAX_VOLUME_STEPS {
+            let step = if up {
+                HAEvent::VolumeUp
+            } else {
+                HAEvent::VolumeDown
+            };
+            self.send_command(step).await?;
+            // Players at their limit just stay there
+            let Some(new) = self.volume_change_from(volume).await else {
+                break;
+            };
+            if (up && new >= target) || (!up && new <= target) {
+                break;
+            }
+            volume = new;
+        }
+        Ok(())
+    }
+
+    /// Waits for HA to report a volume other than `volume`, returning it.
+    async fn volume_change_from(&self, volume: Volume) -> Option<Volume> {
+        tokio::time::timeout(CONFIRM_TIMEOUT, async {
+            loop {
+                let changed = self.metadata_changed.notified();
+                tokio::pin!(changed);
+                changed.as_mut().enable();
+                let current = self.metadata.lock().await.volume;
+                if current != volume {
+                    return current;
+                }
+                changed.await;
+            }
+        })
+        .await
+        .ok()
+    }
+}
+
+impl From<ServiceCallError> for fdo::Error {
+    fn from(e: ServiceCallError) -> Self {
+        match e.code.as_str() {
+            "not_found" => fdo::Error::UnknownObject(e.to_string()),
+            "not_supported" => fdo::Error::NotSupported(e.to_string()),
+            "unauthorized" => fdo::Error::AccessDenied(e.to_string()),
+            "invalid_format" => fdo::Error::InvalidArgs(e.to_string()),
+            _ => fdo::Error::Failed(e.to_string()),
+        }
+    }
+}
+
+impl From<OpenUriError> for fdo::Error {
+    fn from(e: OpenUriError) -> Self {
+        match e {
+            OpenUriError::UnsupportedScheme(_) => fdo::Error::NotSupported(e.to_string()),
+            OpenUriError::InvalidUri(_) | OpenUriError::NotShared(_) => {
+                fdo::Error::InvalidArgs(e.to_string())
+            }
+        }
+    }
 }
 
 impl RootInterface for MyPlayer {
     async fn raise(&self) -> fdo::Result<()> {
+        let Some(mut command) = self.settings.raise.command(&self.entity_id, &self.base_url) else {
+            return Err(fdo::Error::NotSupported(format!(
+                "{} can not be raised",
+                self.entity_id
+            )));
+        };
+        let mut child = command.spawn().map_err(|e| {
+            fdo::Error::SpawnFailed(format!("Could not raise {}: {e}", self.entity_id))
+        })?;
+        let entity_id = self.entity_id.clone();
+        // Browsers can keep running for a long time, so don't hold up the caller
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if !status.success() => {
+                    println!("Raising {entity_id} failed with {status}")
+                }
+                Err(e) => println!("Raising {entity_id} failed: {e}"),
+                Ok(_) => {}
+            }
+        });
         Ok(())
     }
 
     async fn quit(&self) -> fdo::Result<()> {
-        Ok(())
+        if !self.settings.quit_turns_off {
+            return Err(fdo::Error::NotSupported(format!(
+                "Quitting is not enabled for {}",
+                self.entity_id
+            )));
+        }
+        self.require(SupportedFeatures::TURN_OFF, "turning off")
+            .await?;
+        self.send_command(HAEvent::TurnOff).await
     }
 
     async fn can_quit(&self) -> fdo::Result<bool> {
-        Ok(false)
+        Ok(can_quit(
+            &self.settings,
+            self.metadata.lock().await.features,
+        ))
     }
 
     async fn fullscreen(&self) -> fdo::Result<bool> {
@@ -46,140 +577,135 @@ impl RootInterface for MyPlayer {
     }
 
     async fn can_raise(&self) -> fdo::Result<bool> {
-        Ok(false)
+        Ok(self.settings.raise != RaiseAction::None)
     }
 
     async fn has_track_list(&self) -> fdo::Result<bool> {
-        Ok(false)
+        Ok(self.settings.queue.is_some())
     }
 
     async fn identity(&self) -> fdo::Result<String> {
-        Ok("MyPlayer".into())
+        Ok(identity_for(
+            &self.entity_id,
+            &self.settings,
+            &*self.metadata.lock().await,
+        ))
     }
 
     async fn desktop_entry(&self) -> fdo::Result<String> {
-        Ok("HomeAssistantPlayer".to_string())
+        Ok(DESKTOP_ENTRY.to_string())
     }
 
     async fn supported_uri_schemes(&self) -> fdo::Result<Vec<String>> {
-        Ok(vec![])
+        if !self.supports(SupportedFeatures::PLAY_MEDIA).await {
+            return Ok(vec![]);
+        }
+        Ok(self.settings.open_uri.schemes.clone())
     }
 
     async fn supported_mime_types(&self) -> fdo::Result<Vec<String>> {
-        Ok(vec![])
+        if !self.supports(SupportedFeatures::PLAY_MEDIA).await {
+            return Ok(vec![]);
+        }
+        Ok(self.settings.open_uri.mime_types.clone())
     }
 }
 
 impl PlayerInterface for MyPlayer {
     async fn next(&self) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Next))
-            .await;
-        Ok(())
+        self.require(SupportedFeatures::NEXT_TRACK, "next track")
+            .await?;
+        self.send_command(HAEvent::Next).await
     }
 
     async fn previous(&self) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Previous))
-            .await;
-        Ok(())
+        self.require(SupportedFeatures::PREVIOUS_TRACK, "previous track")
+            .await?;
+        self.send_command(HAEvent::Previous).await
     }
 
     async fn pause(&self) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Pause))
-            .await;
-        Ok(())
+        self.require(SupportedFeatures::PAUSE, "pause").await?;
+        self.send_optimistic(HAEvent::Pause, Expected::State(HAState::Paused))
+            .await
     }
 
     async fn play_pause(&self) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((
-                self.entity_id.clone(),
-                if self.metadata.lock().await.playing {
-                    HAEvent::Pause
-                } else {
-                    HAEvent::Play
-                },
-            ))
-            .await;
-        Ok(())
+        let status = playback_status(self.metadata.lock().await.state);
+        if status == PlaybackStatus::Playing {
+            PlayerInterface::pause(self).await
+        } else {
+            PlayerInterface::play(self).await
+        }
     }
 
     async fn stop(&self) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Pause))
-            .await;
-        Ok(())
+        // Players without a stop button at least stop making noise
+        if !self.supports(SupportedFeatures::STOP).await {
+            self.require(SupportedFeatures::PAUSE, "stopping").await?;
+            return PlayerInterface::pause(self).await;
+        }
+        self.send_optimistic(HAEvent::Stop, Expected::State(HAState::Idle))
+            .await
     }
 
     async fn play(&self) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Play))
-            .await;
-        Ok(())
+        self.require(SupportedFeatures::PLAY, "play").await?;
+        self.send_optimistic(HAEvent::Play, Expected::State(HAState::Playing))
+            .await
     }
 
     async fn seek(&self, offset: Time) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Seek(offset.as_secs())))
-            .await;
-        Ok(())
+        self.require(SupportedFeatures::SEEK, "seeking").await?;
+        let (position, duration) = {
+            let metadata = self.metadata.lock().await;
+            (metadata.current_position(), metadata.duration)
+        };
+        // Repeated relative seeks add up, even before HA reported the first one
+        let position = self.pending(Coalesced::Seek).unwrap_or(position);
+        self.seek_to(relative_seek(position, offset, duration))
+            .await
     }
 
-    async fn set_position(&self, _track_id: TrackId, position: Time) -> fdo::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Seek(position.as_secs())))
-            .await;
-        Ok(())
+    async fn set_position(&self, track_id: TrackId, position: Time) -> fdo::Result<()> {
+        self.require(SupportedFeatures::SEEK, "seeking").await?;
+        let target = {
+            let metadata = self.metadata.lock().await;
+            absolute_seek(&track_id, position, &metadata)
+        };
+        self.seek_to(target).await
     }
 
-    async fn open_uri(&self, _uri: String) -> fdo::Result<()> {
-        Ok(())
+    async fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        self.require(SupportedFeatures::PLAY_MEDIA, "playing media")
+            .await?;
+        let media = self.settings.open_uri.resolve(&uri)?;
+        self.send_command(HAEvent::PlayMedia(media)).await
     }
 
     async fn playback_status(&self) -> fdo::Result<PlaybackStatus> {
-        if self.metadata.lock().await.playing {
-            Ok(PlaybackStatus::Playing)
-        } else {
-            Ok(PlaybackStatus::Paused)
-        }
+        Ok(playback_status(self.metadata.lock().await.state))
     }
 
     async fn loop_status(&self) -> fdo::Result<LoopStatus> {
-        Ok(match self.metadata.lock().await.repeat {
-            HALoopStatus::None => LoopStatus::None,
-            HALoopStatus::Track => LoopStatus::Track,
-            HALoopStatus::Playlist => LoopStatus::Playlist,
-        })
+        Ok(loop_status(self.metadata.lock().await.repeat))
     }
 
     async fn set_loop_status(&self, loop_status: LoopStatus) -> mpris_server::zbus::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((
-                self.entity_id.clone(),
-                HAEvent::SetLoop(match loop_status {
-                    LoopStatus::None => HALoopStatus::None,
-                    LoopStatus::Track => HALoopStatus::Track,
-                    LoopStatus::Playlist => HALoopStatus::Playlist,
-                }),
-            ))
-            .await;
-
-        Ok(())
+        self.require(SupportedFeatures::REPEAT_SET, "repeat")
+            .await?;
+        let repeat = match loop_status {
+            LoopStatus::None => HALoopStatus::None,
+            LoopStatus::Track => HALoopStatus::Track,
+            LoopStatus::Playlist => HALoopStatus::Playlist,
+        };
+        Ok(self
+            .send_optimistic(HAEvent::SetLoop(repeat), Expected::Repeat(repeat))
+            .await?)
     }
 
     async fn rate(&self) -> fdo::Result<PlaybackRate> {
-        Ok(1.0)
+        Ok(self.metadata.lock().await.rate)
     }
 
     async fn set_rate(&self, _rate: PlaybackRate) -> mpris_server::zbus::Result<()> {
@@ -191,37 +717,43 @@ impl PlayerInterface for MyPlayer {
     }
 
     async fn set_shuffle(&self, shuffle: bool) -> mpris_server::zbus::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::SetShuffle(shuffle)))
-            .await;
-        Ok(())
+        self.require(SupportedFeatures::SHUFFLE_SET, "shuffle")
+            .await?;
+        Ok(self
+            .send_optimistic(HAEvent::SetShuffle(shuffle), Expected::Shuffle(shuffle))
+            .await?)
     }
 
     async fn metadata(&self) -> fdo::Result<Metadata> {
-        let metadata = self.metadata.lock().await;
-        Ok(Metadata::builder()
-            .title(metadata.title.clone())
-            .artist(vec![metadata.artist.clone()])
-            .length(Time::from_secs(metadata.duration))
-            .art_url(metadata.art_url.clone())
-            .build())
+        Ok(mpris_metadata(&*self.metadata.lock().await))
     }
 
     async fn volume(&self) -> fdo::Result<Volume> {
-        Ok(self.metadata.lock().await.volume)
+        // Keeps sliders from jumping back while a new volume is on its way
+        if let Some(volume) = self.pending(Coalesced::Volume) {
+            return Ok(volume);
+        }
+        Ok(mpris_volume(&*self.metadata.lock().await))
     }
 
     async fn set_volume(&self, volume: Volume) -> mpris_server::zbus::Result<()> {
-        let _ = self
-            .ha_sender
-            .send((self.entity_id.clone(), HAEvent::Volume(volume)))
-            .await;
+        let features = self.metadata.lock().await.features;
+        if !features.contains(SupportedFeatures::VOLUME_SET)
+            && !features.contains(SupportedFeatures::VOLUME_STEP)
+            && !features.contains(SupportedFeatures::VOLUME_MUTE)
+        {
+            return Err(fdo::Error::NotSupported(format!(
+                "{} does not support setting the volume",
+                self.entity_id
+            ))
+            .into());
+        }
+        self.coalesce(Coalesced::Volume, volume.clamp(0.0, 1.0));
         Ok(())
     }
 
     async fn position(&self) -> fdo::Result<Time> {
-        Ok(Time::from_secs(self.metadata.lock().await.position))
+        Ok(to_time(self.metadata.lock().await.current_position()))
     }
 
     async fn minimum_rate(&self) -> fdo::Result<PlaybackRate> {
@@ -233,93 +765,1401 @@ impl PlayerInterface for MyPlayer {
     }
 
     async fn can_go_next(&self) -> fdo::Result<bool> {
-        Ok(true)
+        Ok(self.supports(SupportedFeatures::NEXT_TRACK).await)
     }
 
     async fn can_go_previous(&self) -> fdo::Result<bool> {
-        Ok(true)
+        Ok(self.supports(SupportedFeatures::PREVIOUS_TRACK).await)
     }
 
     async fn can_play(&self) -> fdo::Result<bool> {
-        Ok(true)
+        Ok(self.supports(SupportedFeatures::PLAY).await)
     }
 
     async fn can_pause(&self) -> fdo::Result<bool> {
-        Ok(true)
+        Ok(self.supports(SupportedFeatures::PAUSE).await)
     }
 
     async fn can_seek(&self) -> fdo::Result<bool> {
-        Ok(true)
+        Ok(self.supports(SupportedFeatures::SEEK).await)
     }
 
+    /// Always true, as clients cache it and MPRIS has no signal for it. The
+    /// other `Can*` properties show what the entity supports right now.
     async fn can_control(&self) -> fdo::Result<bool> {
         Ok(true)
     }
 }
 
+impl MyPlayer {
+    async fn seek_to(&self, target: SeekTarget) -> fdo::Result<()> {
+        match target {
+            SeekTarget::Position(position) => self.coalesce(Coalesced::Seek, position),
+            SeekTarget::Next => return PlayerInterface::next(self).await,
+            SeekTarget::Ignored => {}
+        }
+        Ok(())
+    }
+
+    fn queue_source(&self) -> fdo::Result<QueueSource> {
+        self.settings
+            .queue
+            .ok_or_else(|| fdo::Error::NotSupported(format!("{} has no queue", self.entity_id)))
+    }
+
+    /// Finds a track of the queue, along with the source to edit it with.
+    async fn queue_index(&self, track_id: &TrackId) -> fdo::Result<(QueueSource, usize)> {
+        let source = self.queue_source()?;
+        let metadata = self.metadata.lock().await.clone();
+        let queue = self.queue.lock().await;
+        queue_track_ids(&queue, &metadata)
+            .iter()
+            .position(|id| id == track_id)
+            .map(|index| (source, index))
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("{track_id} is not in the queue")))
+    }
+}
+
+impl TrackListInterface for MyPlayer {
+    async fn get_tracks_metadata(&self, track_ids: Vec<TrackId>) -> fdo::Result<Vec<Metadata>> {
+        let metadata = self.metadata.lock().await.clone();
+        let queue = self.queue.lock().await;
+        let known = queue_track_ids(&queue, &metadata);
+        // Unknown ids are left out, as per the MPRIS spec
+        Ok(track_ids
+            .iter()
+            .filter_map(|track_id| {
+                let index = known.iter().position(|id| id == track_id)?;
+                Some(if queue.current == Some(index) {
+                    mpris_metadata(&metadata)
+                } else {
+                    queue_item_metadata(&queue.items[index], track_id.clone())
+                })
+            })
+            .collect())
+    }
+
+    async fn add_track(
+        &self,
+        uri: Uri,
+        after_track: TrackId,
+        set_as_current: bool,
+    ) -> fdo::Result<()> {
+        self.require(SupportedFeatures::MEDIA_ENQUEUE, "adding to the queue")
+            .await?;
+        let media = self.settings.open_uri.resolve(&uri)?;
+        // HA can only add right after the current track or at the end of the queue
+        let enqueue = if set_as_current {
+            Enqueue::Play
+        } else if after_track == track_id_for(&*self.metadata.lock().await) {
+            Enqueue::Next
+        } else {
+            Enqueue::Add
+        };
+        self.send_command(HAEvent::Enqueue(media, enqueue)).await?;
+        self.queue_changed.notify_one();
+        Ok(())
+    }
+
+    async fn remove_track(&self, track_id: TrackId) -> fdo::Result<()> {
+        let (source, index) = self.queue_index(&track_id).await?;
+        if !source.can_edit() {
+            return Err(fdo::Error::NotSupported(format!(
+                "{} does not support removing from the queue",
+                self.entity_id
+            )));
+        }
+        self.send_command(HAEvent::RemoveQueueItem(source, index))
+            .await?;
+        self.queue_changed.notify_one();
+        Ok(())
+    }
+
+    async fn go_to(&self, track_id: TrackId) -> fdo::Result<()> {
+        let (source, index) = self.queue_index(&track_id).await?;
+        let current = self.queue.lock().await.current;
+        if current == Some(index) {
+            return Ok(());
+        }
+        if source.can_edit() {
+            return self
+                .send_command(HAEvent::PlayQueueItem(source, index))
+                .await;
+        }
+        // Without random access, only the next track can be reached
+        if current.map(|current| current + 1) == Some(index) {
+            return PlayerInterface::next(self).await;
+        }
+        Err(fdo::Error::NotSupported(format!(
+            "{} can only skip to the next track",
+            self.entity_id
+        )))
+    }
+
+    async fn tracks(&self) -> fdo::Result<Vec<TrackId>> {
+        let metadata = self.metadata.lock().await.clone();
+        Ok(queue_track_ids(&*self.queue.lock().await, &metadata))
+    }
+
+    async fn can_edit_tracks(&self) -> fdo::Result<bool> {
+        Ok(can_edit_tracks(
+            self.settings.queue,
+            self.metadata.lock().await.features,
+        ))
+    }
+}
+
+impl MyPlayer {
+    /// Collects playlists, favorites and presets from the first two levels
+    /// of the media browser. Local media files are left out.
+    async fn browse_playlists(&self) -> fdo::Result<Vec<BrowseItem>> {
+        browse::playlists(&self.entity_id, |media| {
+            self.call(HAEvent::BrowseMedia(media))
+        })
+        .await
+    }
+}
+
+impl PlaylistsInterface for MyPlayer {
+    async fn activate_playlist(&self, playlist_id: PlaylistId) -> fdo::Result<()> {
+        self.require(SupportedFeatures::PLAY_MEDIA, "playing media")
+            .await?;
+        let media = self
+            .playlists
+            .lock()
+            .await
+            .iter()
+            .find(|p| p.playlist.id == playlist_id)
+            .map(|p| p.media.clone())
+            .ok_or_else(|| {
+                fdo::Error::InvalidArgs(format!("{} is not a known playlist", playlist_id.as_str()))
+            })?;
+        self.send_command(HAEvent::PlayMedia(media)).await
+    }
+
+    async fn get_playlists(
+        &self,
+        index: u32,
+        max_count: u32,
+        order: PlaylistOrdering,
+        reverse_order: bool,
+    ) -> fdo::Result<Vec<Playlist>> {
+        let mut playlists: Vec<Playlist> = self
+            .playlists
+            .lock()
+            .await
+            .iter()
+            .map(|p| p.playlist.clone())
+            .collect();
+        // Anything but alphabetical keeps the order of the media browser
+        if order == PlaylistOrdering::Alphabetical {
+            playlists.sort_by_key(|p| p.name.to_lowercase());
+        }
+        if reverse_order {
+            playlists.reverse();
+        }
+        Ok(playlists
+            .into_iter()
+            .skip(index as usize)
+            .take(max_count as usize)
+            .collect())
+    }
+
+    async fn playlist_count(&self) -> fdo::Result<u32> {
+        Ok(self.playlists.lock().await.len() as u32)
+    }
+
+    async fn orderings(&self) -> fdo::Result<Vec<PlaylistOrdering>> {
+        Ok(vec![
+            PlaylistOrdering::Alphabetical,
+            PlaylistOrdering::UserDefined,
+        ])
+    }
+
+    async fn active_playlist(&self) -> fdo::Result<Option<Playlist>> {
+        let metadata = self.metadata.lock().await.clone();
+        Ok(active_playlist(&self.playlists.lock().await, &metadata))
+    }
+}
+
+/// Bridge specific properties, served next to the MPRIS interfaces.
+struct BridgeInterface {
+    player: MyPlayer,
+}
+
+#[zbus::interface(name = "org.homeassistant.MprisBridge.Player")]
+impl BridgeInterface {
+    /// Whether the entity is muted. Unlike a `Volume` of 0 this keeps the
+    /// volume level, so applets can show a muted icon next to it.
+    #[zbus(property)]
+    async fn muted(&self) -> bool {
+        self.player.metadata.lock().await.muted
+    }
+
+    #[zbus(property)]
+    async fn set_muted(&self, muted: bool) -> zbus::Result<()> {
+        self.player
+            .require(SupportedFeatures::VOLUME_MUTE, "muting")
+            .await?;
+        Ok(self
+            .player
+            .send_optimistic(HAEvent::Mute(muted), Expected::Muted(muted))
+            .await?)
+    }
+
+    #[zbus(property)]
+    async fn entity_id(&self) -> String {
+        self.player.entity_id.clone()
+    }
+
+    /// Whether the connection to HA is up and answering heartbeats.
+    #[zbus(property)]
+    async fn connection_healthy(&self) -> bool {
+        self.player.health.borrow().healthy()
+    }
+
+    /// `connecting`, `connected` or `reconnecting`.
+    #[zbus(property)]
+    async fn connection_state(&self) -> String {
+        self.player.health.borrow().state.as_str().to_string()
+    }
+
+    /// Round trip time of the last heartbeat in microseconds, 0 if unknown.
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn heartbeat_latency(&self) -> u64 {
+        self.player
+            .health
+            .borrow()
+            .latency
+            .map_or(0, |latency| latency.as_micros() as u64)
+    }
+}
+
 pub async fn new_mpris_player(
     entity_id: String,
     start_state: MediaPlayer,
     base_url: String,
+    art_cache: Arc<ArtCache>,
+    settings: PlayerSettings,
     mut rx: Receiver<HAEvent>,
-    ha_sender: Sender<(String, HAEvent)>,
+    connection: HAConnection,
 ) -> eyre::Result<()> {
-    let metadata = json_to_metadata(
+    let mut metadata = json_to_metadata(
         start_state.attributes,
-        start_state.state.contains("playing"),
+        HAState::from(start_state.state.as_str()),
         base_url.clone(),
     )?;
+    // Art is shown once it is downloaded, as art hosts can be slow
+    let art_fetched = Arc::new(Notify::new());
+    let mut remote_art = std::mem::take(&mut metadata.art_url);
+    let mut missing = vec![];
+    metadata.art_url = art_cache.cached_or_missing(&remote_art, &mut missing);
+    art_cache.fetch_in_background(missing, art_fetched.clone());
 
     let metadata_lock = Arc::new(Mutex::new(metadata));
+    let queue_changed = Arc::new(Notify::new());
+    let playlists_changed = Arc::new(Notify::new());
+    let expected_changed = Arc::new(Notify::new());
+    let metadata_changed = Arc::new(Notify::new());
     let media_player = MyPlayer {
         entity_id: entity_id.clone(),
-        ha_sender,
+        base_url: base_url.clone(),
+        ha_sender: connection.commands,
+        health: connection.health.clone(),
         metadata: metadata_lock.clone(),
+        settings,
+        queue: Arc::new(Mutex::new(Queue::default())),
+        queue_changed: queue_changed.clone(),
+        playlists: Arc::new(Mutex::new(vec![])),
+        playlists_changed: playlists_changed.clone(),
+        coalesced: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        expected: Arc::new(std::sync::Mutex::new(vec![])),
+        expected_changed: expected_changed.clone(),
+        metadata_changed: metadata_changed.clone(),
     };
-    let player = Server::new(&entity_id.clone(), media_player).await?;
+    // A player restarted with new settings may find its old server still
+    // holding the bus name for a moment
+    let mut attempts = 0;
+    let player = loop {
+        match Server::new_with_all(&bus_name_suffix(&entity_id), media_player.clone()).await {
+            Err(zbus::Error::NameTaken) if attempts < BUS_NAME_ATTEMPTS => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            result => break result?,
+        }
+    };
+    player
+        .connection()
+        .object_server()
+        .at(
+            MPRIS_OBJECT_PATH,
+            BridgeInterface {
+                player: player.imp().clone(),
+            },
+        )
+        .await?;
+    let player = Arc::new(player);
+    // Fetching the queue and browsing for playlists can take a while, so they
+    // must not hold up the state updates. Dropping the set stops the tasks
+    // along with the player.
+    let mut refreshes = JoinSet::new();
+    refreshes.spawn(keep_queue_fresh(player.clone(), art_cache.clone()));
+    refreshes.spawn(keep_playlists_fresh(player.clone(), art_cache.clone()));
+    queue_changed.notify_one();
+    playlists_changed.notify_one();
 
+    // The last state HA reported, before optimistic changes are applied on top
+    let mut confirmed = metadata_lock.lock().await.clone();
+    let mut health = connection.health;
+    let mut connection_state = health.borrow_and_update().state;
     loop {
-        if let Some(i) = rx.recv().await {
-            match i {
-                HAEvent::Play => {
-                    player
-                        .properties_changed([Property::PlaybackStatus(PlaybackStatus::Playing)])
-                        .await?;
+        let deadline = player.imp().next_deadline();
+        tokio::select! {
+            event = rx.recv() => match event {
+                Some(HAEvent::MetadataUpdated(mut update)) => {
+                    if update.art_url == remote_art {
+                        // Already shown, or still being downloaded
+                        update.art_url = confirmed.art_url.clone();
+                    } else {
+                        remote_art = std::mem::take(&mut update.art_url);
+                        let mut missing = vec![];
+                        update.art_url = art_cache.cached_or_missing(&remote_art, &mut missing);
+                        art_cache.fetch_in_background(missing, art_fetched.clone());
+                    }
+                    confirmed = *update;
                 }
-                HAEvent::Pause => {
-                    player
-                        .properties_changed([Property::PlaybackStatus(PlaybackStatus::Paused)])
+                Some(_) => continue,
+                // The channel closes once the player stops being bridged, which drops the server
+                None => break,
+            },
+            _ = art_fetched.notified() => match art_cache.cached_url(&remote_art) {
+                Some(art_url) => confirmed.art_url = art_url,
+                // Art of an earlier track
+                None => continue,
+            },
+            _ = expected_changed.notified() => {}
+            Ok(()) = health.changed() => {
+                let state = health.borrow_and_update().state;
+                if state != connection_state {
+                    connection_state = state;
+                    let bridge = player
+                        .connection()
+                        .object_server()
+                        .interface::<_, BridgeInterface>(MPRIS_OBJECT_PATH)
                         .await?;
+                    let bridge_ref = bridge.get().await;
+                    bridge_ref.connection_healthy_changed(bridge.signal_context()).await?;
+                    bridge_ref.connection_state_changed(bridge.signal_context()).await?;
                 }
-                HAEvent::MetadataUpdated(metadata_update) => {
-                    player
-                        .properties_changed([
-                            Property::Metadata(
-                                Metadata::builder()
-                                    .title(&metadata_update.title)
-                                    .artist(vec![&metadata_update.artist])
-                                    .length(Time::from_secs(metadata_update.duration))
-                                    .art_url(
-                                        metadata_update.art_url.trim_matches(['\"']).to_string(),
-                                    )
-                                    .build(),
-                            ),
-                            Property::CanSeek(true),
-                            Property::LoopStatus(match metadata_update.repeat {
-                                HALoopStatus::None => LoopStatus::None,
-                                HALoopStatus::Playlist => LoopStatus::Playlist,
-                                HALoopStatus::Track => LoopStatus::Track,
-                            }),
-                            Property::Shuffle(metadata_update.shuffle),
-                        ])
-                        .await?;
-                    {
-                        let mut metadata = metadata_lock.lock().await;
-                        *metadata = metadata_update.clone();
+                continue;
+            }
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => {}
+        }
+        let update = player.imp().reconcile(&confirmed);
+        let previous = std::mem::replace(&mut *metadata_lock.lock().await, update.clone());
+        metadata_changed.notify_waiters();
+        publish(&player, &previous, &update).await?;
+    }
+    Ok(())
+}
+
+/// The name shells show for the player: the configured name, HA's
+/// `friendly_name` or the device name, falling back to the entity id.
+fn identity_for(
+    entity_id: &str,
+    settings: &PlayerSettings,
+    metadata: &MediaPlayerMetadata,
+) -> String {
+    settings
+        .name
+        .as_ref()
+        .or(metadata.friendly_name.as_ref())
+        .or(settings.device_name.as_ref())
+        .cloned()
+        .unwrap_or_else(|| entity_id.to_string())
+}
+
+/// Maps an entity id onto the part of the bus name after `org.mpris.MediaPlayer2.`,
+/// e.g. `media_player.living_room` becomes `homeassistant.living_room`.
+///
+/// Bus name elements can not start with a digit, which object ids can. HA
+/// object ids never start with an underscore, so prefixing one can not clash
+/// with another entity. Characters bus names can not have become `_`, and as
+/// e.g. `a-b` and `a_b` would then clash, such names and names too long for
+/// the bus get a hash of the entity id appended.
+fn bus_name_suffix(entity_id: &str) -> String {
+    let object_id = entity_id
+        .split_once('.')
+        .map_or(entity_id, |(_, object_id)| object_id);
+    let mut element: String = object_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let replaced = element != object_id;
+    if element.is_empty() || element.starts_with(|c: char| c.is_ascii_digit()) {
+        element.insert(0, '_');
+    }
+    if replaced || element.len() > MAX_BUS_NAME_LENGTH {
+        let mut hasher = DefaultHasher::new();
+        entity_id.hash(&mut hasher);
+        element.truncate(MAX_BUS_NAME_LENGTH - 17);
+        element.push_str(&format!("_{:016x}", hasher.finish()));
+    }
+    format!("homeassistant.{element}")
+}
+
+/// Announces the differences between what clients saw before and `update`.
+async fn publish(
+    player: &Server<MyPlayer>,
+    previous: &MediaPlayerMetadata,
+    update: &MediaPlayerMetadata,
+) -> eyre::Result<()> {
+    let imp = player.imp();
+    let identity = identity_for(&imp.entity_id, &imp.settings, update);
+    if identity != identity_for(&imp.entity_id, &imp.settings, previous) {
+        player
+            .properties_changed([Property::Identity(identity)])
+            .await?;
+    }
+    player
+        .properties_changed(capability_changes(previous.features, update.features))
+        .await?;
+    let quit = can_quit(&imp.settings, update.features);
+    if quit != can_quit(&imp.settings, previous.features) {
+        player.properties_changed([Property::CanQuit(quit)]).await?;
+    }
+    let can_edit = can_edit_tracks(imp.settings.queue, update.features);
+    if can_edit != can_edit_tracks(imp.settings.queue, previous.features) {
+        player
+            .track_list_properties_changed([TrackListProperty::CanEditTracks(can_edit)])
+            .await?;
+    }
+    // Everything else is compared one by one, as notification daemons show a
+    // "now playing" popup for every `Metadata` change
+    let mut changes = vec![];
+    if playback_status(update.state) != playback_status(previous.state) {
+        changes.push(Property::PlaybackStatus(playback_status(update.state)));
+    }
+    let metadata = mpris_metadata(update);
+    if metadata != mpris_metadata(previous) {
+        changes.push(Property::Metadata(metadata));
+    }
+    if update.repeat != previous.repeat {
+        changes.push(Property::LoopStatus(loop_status(update.repeat)));
+    }
+    if update.shuffle != previous.shuffle {
+        changes.push(Property::Shuffle(update.shuffle));
+    }
+    if mpris_volume(update) != mpris_volume(previous) {
+        changes.push(Property::Volume(mpris_volume(update)));
+    }
+    player.properties_changed(changes).await?;
+    if previous.muted != update.muted {
+        let bridge = player
+            .connection()
+            .object_server()
+            .interface::<_, BridgeInterface>(MPRIS_OBJECT_PATH)
+            .await?;
+        bridge
+            .get()
+            .await
+            .muted_changed(bridge.signal_context())
+            .await?;
+    }
+    if is_seek(previous, update, Utc::now()) {
+        player
+            .emit(Signal::Seeked {
+                position: to_time(update.current_position()),
+            })
+            .await?;
+    }
+    if track_id_for(previous) != track_id_for(update)
+        || (previous.queue_position, previous.queue_size)
+            != (update.queue_position, update.queue_size)
+    {
+        imp.queue_changed.notify_one();
+    }
+    // Each source (e.g. a TV app or a streaming service) has favorites of its own
+    if previous.source != update.source
+        || previous.features.contains(SupportedFeatures::BROWSE_MEDIA)
+            != update.features.contains(SupportedFeatures::BROWSE_MEDIA)
+    {
+        imp.playlists_changed.notify_one();
+    } else {
+        let playlists = imp.playlists.lock().await.clone();
+        let active = active_playlist(&playlists, update);
+        if active != active_playlist(&playlists, previous) {
+            player
+                .playlists_properties_changed([PlaylistsProperty::ActivePlaylist(active)])
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// The `Can*` properties that differ between two feature sets, e.g. when a TV
+/// switches from a streaming app to live TV.
+fn capability_changes(old: SupportedFeatures, new: SupportedFeatures) -> Vec<Property> {
+    let changed = |feature| old.contains(feature) != new.contains(feature);
+    let mut changes = vec![];
+    if changed(SupportedFeatures::NEXT_TRACK) {
+        changes.push(Property::CanGoNext(
+            new.contains(SupportedFeatures::NEXT_TRACK),
+        ));
+    }
+    if changed(SupportedFeatures::PREVIOUS_TRACK) {
+        changes.push(Property::CanGoPrevious(
+            new.contains(SupportedFeatures::PREVIOUS_TRACK),
+        ));
+    }
+    if changed(SupportedFeatures::PLAY) {
+        changes.push(Property::CanPlay(new.contains(SupportedFeatures::PLAY)));
+    }
+    if changed(SupportedFeatures::PAUSE) {
+        changes.push(Property::CanPause(new.contains(SupportedFeatures::PAUSE)));
+    }
+    if changed(SupportedFeatures::SEEK) {
+        changes.push(Property::CanSeek(new.contains(SupportedFeatures::SEEK)));
+    }
+    changes
+}
+
+fn can_quit(settings: &PlayerSettings, features: SupportedFeatures) -> bool {
+    settings.quit_turns_off && features.contains(SupportedFeatures::TURN_OFF)
+}
+
+/// MPRIS ties `CanEditTracks` to both `AddTrack` and `RemoveTrack`, so
+/// queues without removal can not be edited, even though tracks can be added.
+fn can_edit_tracks(queue: Option<QueueSource>, features: SupportedFeatures) -> bool {
+    queue.is_some_and(|queue| queue.can_edit())
+        && features.contains(SupportedFeatures::MEDIA_ENQUEUE)
+}
+
+/// Fetches the queue whenever asked to. Requests made while a fetch is
+/// running are merged into one.
+async fn keep_queue_fresh(player: Arc<Server<MyPlayer>>, art_cache: Arc<ArtCache>) {
+    let changed = player.imp().queue_changed.clone();
+    loop {
+        changed.notified().await;
+        if let Err(e) = refresh_queue(&player, &art_cache).await {
+            println!(
+                "Could not announce the queue of {}: {e}",
+                player.imp().entity_id
+            );
+        }
+    }
+}
+
+/// Fetches the queue from HA and announces it when it changed. Integrations
+/// without a working `get_queue` service just keep an empty track list.
+async fn refresh_queue(player: &Server<MyPlayer>, art_cache: &Arc<ArtCache>) -> eyre::Result<()> {
+    let imp = player.imp();
+    let Some(source) = imp.settings.queue else {
+        return Ok(());
+    };
+    let result = match imp.call(HAEvent::GetQueue(source)).await {
+        Ok(result) => result,
+        Err(e) => {
+            println!("Could not fetch the queue of {}: {e}", imp.entity_id);
+            return Ok(());
+        }
+    };
+    let metadata = imp.metadata.lock().await.clone();
+    let mut queue = source.parse(&imp.entity_id, &result, &metadata);
+    let mut missing = vec![];
+    for item in &mut queue.items {
+        item.art_url = art_cache.cached_or_missing(&item.art_url, &mut missing);
+    }
+    // The queue is fetched again with the art once it is downloaded
+    art_cache.fetch_in_background(missing, imp.queue_changed.clone());
+
+    let tracks = queue_track_ids(&queue, &metadata);
+    let current_track = match queue.current {
+        Some(_) => track_id_for(&metadata),
+        None => TrackId::NO_TRACK,
+    };
+    {
+        let mut known = imp.queue.lock().await;
+        if *known == queue {
+            return Ok(());
+        }
+        *known = queue;
+    }
+    player
+        .track_list_emit(TrackListSignal::TrackListReplaced {
+            tracks,
+            current_track,
+        })
+        .await?;
+    player
+        .track_list_properties_changed([TrackListProperty::Tracks])
+        .await?;
+    Ok(())
+}
+
+/// Browses for the playlists whenever asked to. Requests made while browsing
+/// are merged into one.
+async fn keep_playlists_fresh(player: Arc<Server<MyPlayer>>, art_cache: Arc<ArtCache>) {
+    let changed = player.imp().playlists_changed.clone();
+    loop {
+        changed.notified().await;
+        if let Err(e) = refresh_playlists(&player, &art_cache).await {
+            println!(
+                "Could not announce the playlists of {}: {e}",
+                player.imp().entity_id
+            );
+        }
+    }
+}
+
+/// Browses HA for the player's playlists and announces them when they changed.
+async fn refresh_playlists(
+    player: &Server<MyPlayer>,
+    art_cache: &Arc<ArtCache>,
+) -> eyre::Result<()> {
+    let imp = player.imp();
+    let items = if imp.supports(SupportedFeatures::BROWSE_MEDIA).await {
+        match imp.browse_playlists().await {
+            Ok(items) => items,
+            Err(e) => {
+                println!("Could not browse the media of {}: {e}", imp.entity_id);
+                return Ok(());
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    let mut playlists = Vec::with_capacity(items.len());
+    let mut missing = vec![];
+    for item in items {
+        let icon = match item.thumbnail.map(|t| validate_art_url(t, &imp.base_url)) {
+            Some(Ok(url)) => art_cache.cached_or_missing(url.as_str(), &mut missing),
+            _ => String::new(),
+        };
+        playlists.push(BrowsedPlaylist {
+            playlist: Playlist {
+                id: playlist_id_for(&item.media),
+                name: item.title,
+                icon,
+            },
+            media: item.media,
+        });
+    }
+    art_cache.fetch_in_background(missing, imp.playlists_changed.clone());
+
+    let metadata = imp.metadata.lock().await.clone();
+    let active = active_playlist(&playlists, &metadata);
+    let count = playlists.len() as u32;
+    {
+        let mut known = imp.playlists.lock().await;
+        if *known == playlists {
+            return Ok(());
+        }
+        *known = playlists;
+    }
+    player
+        .playlists_properties_changed([
+            PlaylistsProperty::PlaylistCount(count),
+            PlaylistsProperty::ActivePlaylist(active),
+        ])
+        .await?;
+    Ok(())
+}
+
+fn playlist_id_for(media: &MediaRequest) -> PlaylistId {
+    let mut hasher = DefaultHasher::new();
+    media.hash(&mut hasher);
+    PlaylistId::try_from(format!(
+        "/org/homeassistant/mpris_bridge/playlist/{:016x}",
+        hasher.finish()
+    ))
+    .unwrap_or_default()
+}
+
+/// The playlist that is playing, going by the content id or playlist name HA reports.
+fn active_playlist(
+    playlists: &[BrowsedPlaylist],
+    metadata: &MediaPlayerMetadata,
+) -> Option<Playlist> {
+    playlists
+        .iter()
+        .find(|p| {
+            metadata.content_id.as_ref() == Some(&p.media.content_id)
+                || metadata.playlist.as_ref() == Some(&p.playlist.name)
+        })
+        .map(|p| p.playlist.clone())
+}
+
+/// Track ids for the queue. The playing item shares its id with the
+/// `Metadata` property, the others are told apart by their position as the
+/// same song can be queued more than once.
+fn queue_track_ids(queue: &Queue, metadata: &MediaPlayerMetadata) -> Vec<TrackId> {
+    queue
+        .items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| {
+            if queue.current == Some(index) {
+                return track_id_for(metadata);
+            }
+            let mut hasher = DefaultHasher::new();
+            item.content_id.hash(&mut hasher);
+            item.title.hash(&mut hasher);
+            item.artists.hash(&mut hasher);
+            item.album.hash(&mut hasher);
+            TrackId::try_from(format!(
+                "/org/homeassistant/mpris_bridge/queue/{index}_{:016x}",
+                hasher.finish()
+            ))
+            .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn queue_item_metadata(item: &QueueItem, track_id: TrackId) -> Metadata {
+    let mut builder = Metadata::builder()
+        .trackid(track_id)
+        .title(&item.title)
+        .artist(&item.artists);
+    if let Some(album) = &item.album {
+        builder = builder.album(album);
+    }
+    if item.duration > 0.0 {
+        builder = builder.length(to_time(item.duration));
+    }
+    if !item.art_url.is_empty() {
+        builder = builder.art_url(&item.art_url);
+    }
+    if let Some(content_id) = &item.content_id {
+        if url::Url::parse(content_id).is_ok() {
+            builder = builder.url(content_id);
+        }
+        builder = builder.other("homeassistant:contentId", content_id.clone());
+    }
+    builder.build()
+}
+
+/// Anything that is neither playing nor paused (off, idle, standby, ...) counts as stopped.
+fn playback_status(state: HAState) -> PlaybackStatus {
+    match state {
+        HAState::Playing | HAState::Buffering => PlaybackStatus::Playing,
+        HAState::Paused => PlaybackStatus::Paused,
+        HAState::Off
+        | HAState::On
+        | HAState::Idle
+        | HAState::Standby
+        | HAState::Unavailable
+        | HAState::Unknown => PlaybackStatus::Stopped,
+    }
+}
+
+fn loop_status(repeat: HALoopStatus) -> LoopStatus {
+    match repeat {
+        HALoopStatus::None => LoopStatus::None,
+        HALoopStatus::Track => LoopStatus::Track,
+        HALoopStatus::Playlist => LoopStatus::Playlist,
+    }
+}
+
+/// Muted players report a `Volume` of 0, as MPRIS has no mute of its own.
+fn mpris_volume(metadata: &MediaPlayerMetadata) -> Volume {
+    if metadata.muted {
+        0.0
+    } else {
+        metadata.volume
+    }
+}
+
+fn to_time(secs: f64) -> Time {
+    Time::from_micros((secs * 1_000_000.0).round() as i64)
+}
+
+fn to_secs(time: Time) -> f64 {
+    time.as_micros() as f64 / 1_000_000.0
+}
+
+/// A track id that stays the same for as long as the same track is playing.
+fn track_id_for(metadata: &MediaPlayerMetadata) -> TrackId {
+    let mut hasher = DefaultHasher::new();
+    metadata.content_id.hash(&mut hasher);
+    metadata.title.hash(&mut hasher);
+    metadata.artists.hash(&mut hasher);
+    metadata.album.hash(&mut hasher);
+    // The content id identifies the track on its own, except for streams like
+    // radio where it stays the same while the title changes
+    if metadata.content_id.is_none() {
+        metadata.duration.to_bits().hash(&mut hasher);
+    }
+    TrackId::try_from(format!(
+        "/org/homeassistant/mpris_bridge/track/{:016x}",
+        hasher.finish()
+    ))
+    .unwrap_or_default()
+}
+
+/// Maps HA's media attributes onto the `xesam:` and `mpris:` keys. Attributes
+/// without an MPRIS equivalent go under the `homeassistant:` prefix.
+fn mpris_metadata(metadata: &MediaPlayerMetadata) -> Metadata {
+    let mut builder = Metadata::builder()
+        .trackid(track_id_for(metadata))
+        .title(&metadata.title)
+        .artist(&metadata.artists)
+        .length(to_time(metadata.duration))
+        .art_url(&metadata.art_url);
+
+    // TV shows and radio have no album, the show or channel is the closest thing
+    if let Some(album) = metadata
+        .album
+        .as_ref()
+        .or(metadata.series.as_ref())
+        .or(metadata.channel.as_ref())
+    {
+        builder = builder.album(album);
+    }
+    if !metadata.album_artists.is_empty() {
+        builder = builder.album_artist(&metadata.album_artists);
+    }
+    if let Some(track_number) = metadata.track_number.or_else(|| {
+        metadata
+            .episode
+            .as_ref()
+            .and_then(|episode| episode.parse().ok())
+    }) {
+        builder = builder.track_number(track_number);
+    }
+    if let Some(season) = metadata
+        .season
+        .as_ref()
+        .and_then(|season| season.parse().ok())
+    {
+        builder = builder.disc_number(season);
+    }
+    if let Some(url) = metadata
+        .content_id
+        .as_ref()
+        .filter(|id| url::Url::parse(id).is_ok())
+    {
+        builder = builder.url(url);
+    }
+
+    let extras = [
+        ("homeassistant:contentId", &metadata.content_id),
+        ("homeassistant:series", &metadata.series),
+        ("homeassistant:season", &metadata.season),
+        ("homeassistant:episode", &metadata.episode),
+        ("homeassistant:channel", &metadata.channel),
+        ("homeassistant:playlist", &metadata.playlist),
+        ("homeassistant:appName", &metadata.app_name),
+    ];
+    for (key, value) in extras {
+        if let Some(value) = value {
+            builder = builder.other(key, value.clone());
+        }
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use serde_json::json;
+
+    use super::*;
+
+    fn metadata(attributes: Value, state: HAState) -> MediaPlayerMetadata {
+        json_to_metadata(
+            serde_json::from_value(attributes).unwrap(),
+            state,
+            "http://homeassistant.local:8123".to_string(),
+        )
+        .unwrap()
+    }
+
+    /// A player for an entity with `attributes`, with the receiving end of
+    /// the commands it sends to HA.
+    fn player(attributes: Value) -> (MyPlayer, Receiver<(String, HAEvent, CommandReply)>) {
+        let (ha_sender, commands) = tokio::sync::mpsc::channel(10);
+        let (_, health) = watch::channel(ConnectionHealth::default());
+        let player = MyPlayer {
+            entity_id: "media_player.kitchen".to_string(),
+            base_url: "http://homeassistant.local:8123".to_string(),
+            ha_sender,
+            health,
+            metadata: Arc::new(Mutex::new(metadata(attributes, HAState::Playing))),
+            settings: PlayerSettings {
+                coalesce_window: Duration::from_millis(100),
+                ..PlayerSettings::default()
+            },
+            queue: Arc::default(),
+            queue_changed: Arc::default(),
+            playlists: Arc::default(),
+            playlists_changed: Arc::default(),
+            coalesced: Arc::default(),
+            expected: Arc::default(),
+            expected_changed: Arc::default(),
+            metadata_changed: Arc::default(),
+        };
+        (player, commands)
+    }
+
+    /// Answers the player's commands like HA, moving the volume by `step`
+    /// for every `volume_up`, until `until` finishes. Returns the commands.
+    async fn answer_volume_steps<T>(
+        player: &MyPlayer,
+        commands: &mut Receiver<(String, HAEvent, CommandReply)>,
+        step: f64,
+        until: impl std::future::Future<Output = T>,
+    ) -> Vec<HAEvent> {
+        tokio::pin!(until);
+        let mut received = vec![];
+        loop {
+            tokio::select! {
+                _ = &mut until => return received,
+                Some((_, event, reply)) = commands.recv() => {
+                    let _ = reply.send(Ok(Value::Null));
+                    if matches!(event, HAEvent::VolumeUp) {
+                        player.metadata.lock().await.volume += step;
+                        player.metadata_changed.notify_waiters();
                     }
+                    received.push(event);
                 }
-                _ => {}
             }
         }
     }
+
+    fn track_at(position: f64, updated_at: DateTime<Utc>, state: HAState) -> MediaPlayerMetadata {
+        metadata(
+            json!({
+                "media_title": "Song",
+                "media_duration": 200,
+                "media_position": position,
+                "media_position_updated_at": updated_at.to_rfc3339(),
+            }),
+            state,
+        )
+    }
+
+    #[test]
+    fn capabilities_that_did_not_change_are_not_announced() {
+        let features = SupportedFeatures::PAUSE | SupportedFeatures::SEEK;
+        assert_eq!(capability_changes(features, features), []);
+    }
+
+    #[test]
+    fn gained_and_lost_capabilities_are_announced() {
+        let old =
+            SupportedFeatures::PAUSE | SupportedFeatures::SEEK | SupportedFeatures::NEXT_TRACK;
+        let new =
+            SupportedFeatures::PAUSE | SupportedFeatures::PLAY | SupportedFeatures::PREVIOUS_TRACK;
+        assert_eq!(
+            capability_changes(old, new),
+            [
+                Property::CanGoNext(false),
+                Property::CanGoPrevious(true),
+                Property::CanPlay(true),
+                Property::CanSeek(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn losing_every_feature_disables_every_control() {
+        let all = SupportedFeatures::PAUSE
+            | SupportedFeatures::SEEK
+            | SupportedFeatures::NEXT_TRACK
+            | SupportedFeatures::PREVIOUS_TRACK
+            | SupportedFeatures::PLAY;
+        assert_eq!(
+            capability_changes(all, SupportedFeatures::default()),
+            [
+                Property::CanGoNext(false),
+                Property::CanGoPrevious(false),
+                Property::CanPlay(false),
+                Property::CanPause(false),
+                Property::CanSeek(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_volume_mutes_players_that_can_mute() {
+        let features = SupportedFeatures::VOLUME_SET | SupportedFeatures::VOLUME_MUTE;
+        assert_eq!(
+            volume_change(0.0, features, false),
+            Some(VolumeChange {
+                mute: Some(true),
+                level: None
+            })
+        );
+        assert_eq!(
+            volume_change(0.0, features, true),
+            Some(VolumeChange {
+                mute: None,
+                level: None
+            })
+        );
+        // Without a mute the volume goes down to 0
+        assert_eq!(
+            volume_change(0.0, SupportedFeatures::VOLUME_SET, false),
+            Some(VolumeChange {
+                mute: None,
+                level: Some(VolumeLevel::Set(0.0))
+            })
+        );
+    }
+
+    #[test]
+    fn raising_the_volume_unmutes() {
+        let features = SupportedFeatures::VOLUME_SET | SupportedFeatures::VOLUME_MUTE;
+        assert_eq!(
+            volume_change(0.4, features, true),
+            Some(VolumeChange {
+                mute: Some(false),
+                level: Some(VolumeLevel::Set(0.4))
+            })
+        );
+        assert_eq!(
+            volume_change(0.4, SupportedFeatures::VOLUME_MUTE, true),
+            Some(VolumeChange {
+                mute: Some(false),
+                level: None
+            })
+        );
+    }
+
+    #[test]
+    fn volume_is_stepped_without_volume_set() {
+        assert_eq!(
+            volume_change(0.4, SupportedFeatures::VOLUME_STEP, false),
+            Some(VolumeChange {
+                mute: None,
+                level: Some(VolumeLevel::StepTowards(0.4))
+            })
+        );
+        assert_eq!(volume_change(0.4, SupportedFeatures::PAUSE, false), None);
+    }
+
+    #[test]
+    fn muted_players_report_no_volume() {
+        let mut metadata = metadata(json!({"volume_level": 0.6}), HAState::Playing);
+        assert_eq!(mpris_volume(&metadata), 0.6);
+        metadata.muted = true;
+        assert_eq!(mpris_volume(&metadata), 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn step_only_players_step_until_they_reach_the_volume() {
+        // VOLUME_STEP
+        let (player, mut commands) =
+            player(json!({"supported_features": 1024, "volume_level": 0.2}));
+        let stepping = player.apply_volume(0.45);
+        let sent = answer_volume_steps(&player, &mut commands, 0.1, stepping).await;
+        assert_eq!(sent.len(), 3, "{sent:?}");
+        assert!(sent.iter().all(|e| matches!(e, HAEvent::VolumeUp)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn step_only_players_stop_when_the_volume_does_not_move() {
+        let (player, mut commands) =
+            player(json!({"supported_features": 1024, "volume_level": 1.0}));
+        // HA never reports a new volume, e.g. for players it does not poll
+        let stepping = player.apply_volume(0.5);
+        let sent = answer_volume_steps(&player, &mut commands, 0.0, stepping).await;
+        assert!(matches!(sent[..], [HAEvent::VolumeDown]), "{sent:?}");
+    }
+
+    #[test]
+    fn only_queues_that_can_remove_tracks_can_be_edited() {
+        let features = SupportedFeatures::MEDIA_ENQUEUE;
+        assert!(can_edit_tracks(Some(QueueSource::Sonos), features));
+        assert!(!can_edit_tracks(
+            Some(QueueSource::MusicAssistant),
+            features
+        ));
+        assert!(!can_edit_tracks(None, features));
+        assert!(!can_edit_tracks(
+            Some(QueueSource::Sonos),
+            SupportedFeatures::default()
+        ));
+    }
+
+    /// Checks the name mpris-server requests for `entity_id` is a valid bus name.
+    fn bus_name(entity_id: &str) -> String {
+        let name = format!("org.mpris.MediaPlayer2.{}", bus_name_suffix(entity_id));
+        assert!(
+            zbus::names::WellKnownName::try_from(name.as_str()).is_ok(),
+            "{name}"
+        );
+        name
+    }
+
+    #[test]
+    fn bus_names_follow_the_object_id() {
+        assert_eq!(
+            bus_name("media_player.living_room"),
+            "org.mpris.MediaPlayer2.homeassistant.living_room"
+        );
+    }
+
+    #[test]
+    fn bus_names_do_not_start_with_a_digit() {
+        assert_eq!(
+            bus_name("media_player.2nd_floor"),
+            "org.mpris.MediaPlayer2.homeassistant._2nd_floor"
+        );
+    }
+
+    #[test]
+    fn replaced_characters_do_not_make_names_clash() {
+        let dashed = bus_name("media_player.a-b");
+        assert!(dashed.starts_with("org.mpris.MediaPlayer2.homeassistant.a_b_"));
+        assert_ne!(dashed, bus_name("media_player.a_b"));
+        assert_ne!(
+            bus_name("media_player.küche"),
+            bus_name("media_player.kñche")
+        );
+    }
+
+    #[test]
+    fn long_names_are_shortened_without_clashing() {
+        let long = "x".repeat(300);
+        let first = bus_name(&format!("media_player.{long}_1"));
+        let second = bus_name(&format!("media_player.{long}_2"));
+        let suffix = bus_name_suffix(&format!("media_player.{long}_1"));
+        assert!(suffix.len() <= "homeassistant.".len() + MAX_BUS_NAME_LENGTH);
+        assert_ne!(first, second);
+    }
+
+    /// Shows `change` as pending until `CONFIRM_TIMEOUT` from now.
+    fn expect(player: &MyPlayer, change: Expected) {
+        player.expected.lock().unwrap().push(Expectation {
+            change,
+            deadline: Instant::now() + CONFIRM_TIMEOUT,
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_changes_show_until_ha_confirms_them() {
+        let (player, _commands) = player(json!({"volume_level": 0.5}));
+        let playing = metadata(json!({"volume_level": 0.5}), HAState::Playing);
+        expect(&player, Expected::State(HAState::Paused));
+        expect(&player, Expected::Volume(0.8));
+
+        let shown = player.reconcile(&playing);
+        assert_eq!(shown.state, HAState::Paused);
+        assert_eq!(shown.volume, 0.8);
+
+        // HA confirms the pause, the volume is still on its way
+        let paused = metadata(json!({"volume_level": 0.5}), HAState::Paused);
+        let shown = player.reconcile(&paused);
+        assert_eq!((shown.state, shown.volume), (HAState::Paused, 0.8));
+        assert_eq!(player.expected.lock().unwrap().len(), 1);
+
+        // Players round the volume to their own steps
+        let rounded = metadata(json!({"volume_level": 0.795}), HAState::Paused);
+        assert_eq!(player.reconcile(&rounded).volume, 0.795);
+        assert!(player.expected.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn contradicted_changes_roll_back_at_the_deadline() {
+        let (player, _commands) = player(json!({}));
+        let playing = metadata(json!({}), HAState::Playing);
+        expect(&player, Expected::Shuffle(true));
+
+        // HA reporting something else, e.g. for an unrelated attribute, is
+        // not an answer yet
+        assert!(player.reconcile(&playing).shuffle);
+        tokio::time::advance(CONFIRM_TIMEOUT - Duration::from_millis(1)).await;
+        assert!(player.reconcile(&playing).shuffle);
+        assert_eq!(
+            player.next_deadline(),
+            Some(Instant::now() + Duration::from_millis(1))
+        );
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(!player.reconcile(&playing).shuffle);
+        assert!(player.expected.lock().unwrap().is_empty());
+        assert_eq!(player.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_changes_roll_back_right_away() {
+        let (player, mut commands) = player(json!({"supported_features": 1 << 18}));
+        let answer = async {
+            let (_, event, reply) = commands.recv().await.unwrap();
+            assert!(matches!(event, HAEvent::SetLoop(HALoopStatus::Track)));
+            let _ = reply.send(Err(ServiceCallError {
+                code: "not_supported".to_string(),
+                message: "Repeat is not available".to_string(),
+            }));
+        };
+        let (result, ()) = tokio::join!(
+            player.send_optimistic(
+                HAEvent::SetLoop(HALoopStatus::Track),
+                Expected::Repeat(HALoopStatus::Track)
+            ),
+            answer
+        );
+
+        assert!(matches!(result, Err(fdo::Error::NotSupported(_))));
+        assert!(player.expected.lock().unwrap().is_empty());
+        let idle = metadata(json!({}), HAState::Idle);
+        assert_eq!(player.reconcile(&idle).repeat, HALoopStatus::None);
+    }
+
+    /// The commands the player sends to HA until it goes quiet, answering each.
+    async fn commands_sent(
+        commands: &mut Receiver<(String, HAEvent, CommandReply)>,
+    ) -> Vec<HAEvent> {
+        let mut sent = vec![];
+        while let Ok(Some((_, event, reply))) =
+            tokio::time::timeout(Duration::from_secs(1), commands.recv()).await
+        {
+            let _ = reply.send(Ok(Value::Null));
+            sent.push(event);
+        }
+        sent
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn volume_bursts_send_the_last_volume_once() {
+        // VOLUME_SET
+        let (player, mut commands) = player(json!({"supported_features": 4, "volume_level": 0.2}));
+        for volume in [0.3, 0.4, 0.5, 0.6] {
+            PlayerInterface::set_volume(&player, volume).await.unwrap();
+            tokio::time::advance(Duration::from_millis(20)).await;
+        }
+        // Sliders do not jump back while the volume is on its way
+        assert_eq!(PlayerInterface::volume(&player).await.unwrap(), 0.6);
+
+        let sent = commands_sent(&mut commands).await;
+        assert!(
+            matches!(sent[..], [HAEvent::Volume(v)] if v == 0.6),
+            "{sent:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seek_bursts_add_up_to_one_seek() {
+        // SEEK
+        let (player, mut commands) = player(json!({
+            "supported_features": 2,
+            "media_title": "Song",
+            "media_duration": 200,
+            "media_position": 30,
+        }));
+        for _ in 0..3 {
+            PlayerInterface::seek(&player, Time::from_secs(10))
+                .await
+                .unwrap();
+        }
+
+        let sent = commands_sent(&mut commands).await;
+        assert!(
+            matches!(sent[..], [HAEvent::Seek(p)] if p == 60.0),
+            "{sent:?}"
+        );
+    }
+
+    #[test]
+    fn seeking_moves_from_the_position() {
+        assert_eq!(
+            relative_seek(30.0, Time::from_secs(10), 200.0),
+            SeekTarget::Position(40.0)
+        );
+        assert_eq!(
+            relative_seek(30.0, Time::from_secs(-10), 200.0),
+            SeekTarget::Position(20.0)
+        );
+    }
+
+    #[test]
+    fn seeking_before_the_start_goes_to_the_start() {
+        assert_eq!(
+            relative_seek(5.0, Time::from_secs(-10), 200.0),
+            SeekTarget::Position(0.0)
+        );
+    }
+
+    #[test]
+    fn seeking_past_the_end_goes_to_the_next_track() {
+        assert_eq!(
+            relative_seek(195.0, Time::from_secs(10), 200.0),
+            SeekTarget::Next
+        );
+        // Streams have no end to seek past
+        assert_eq!(
+            relative_seek(195.0, Time::from_secs(10), 0.0),
+            SeekTarget::Position(205.0)
+        );
+    }
+
+    #[test]
+    fn set_position_seeks_within_the_current_track() {
+        let track = track_at(0.0, Utc::now(), HAState::Playing);
+        let track_id = track_id_for(&track);
+        assert_eq!(
+            absolute_seek(&track_id, Time::from_secs(50), &track),
+            SeekTarget::Position(50.0)
+        );
+        assert_eq!(
+            absolute_seek(&track_id, Time::from_secs(200), &track),
+            SeekTarget::Position(200.0)
+        );
+    }
+
+    #[test]
+    fn set_position_ignores_stale_tracks_and_out_of_range_positions() {
+        let track = track_at(0.0, Utc::now(), HAState::Playing);
+        let track_id = track_id_for(&track);
+        let stale = TrackId::try_from("/org/homeassistant/mpris_bridge/track/0").unwrap();
+        assert_eq!(
+            absolute_seek(&stale, Time::from_secs(50), &track),
+            SeekTarget::Ignored
+        );
+        assert_eq!(
+            absolute_seek(&track_id, Time::from_secs(-1), &track),
+            SeekTarget::Ignored
+        );
+        assert_eq!(
+            absolute_seek(&track_id, Time::from_secs(201), &track),
+            SeekTarget::Ignored
+        );
+    }
+
+    #[test]
+    fn position_jumps_are_seeks() {
+        let start = Utc::now();
+        let previous = track_at(10.0, start, HAState::Playing);
+        let update = track_at(60.0, start + TimeDelta::seconds(2), HAState::Playing);
+        assert!(is_seek(&previous, &update, start + TimeDelta::seconds(3)));
+    }
+
+    #[test]
+    fn playback_moving_on_is_not_a_seek() {
+        let start = Utc::now();
+        let previous = track_at(10.0, start, HAState::Playing);
+        // HA reporting the position again, a little off from the extrapolation
+        let update = track_at(12.5, start + TimeDelta::seconds(2), HAState::Playing);
+        assert!(!is_seek(&previous, &update, start + TimeDelta::seconds(3)));
+        let paused = track_at(12.0, start + TimeDelta::seconds(2), HAState::Paused);
+        assert!(!is_seek(&previous, &paused, start + TimeDelta::seconds(2)));
+    }
+
+    #[test]
+    fn a_new_track_starting_is_not_a_seek() {
+        let start = Utc::now();
+        let previous = track_at(150.0, start, HAState::Playing);
+        let mut next = track_at(0.0, start + TimeDelta::seconds(1), HAState::Playing);
+        next.title = "Next song".to_string();
+        assert!(!is_seek(&previous, &next, start + TimeDelta::seconds(1)));
+    }
 }
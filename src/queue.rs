@@ -0,0 +1,270 @@
+use serde_json::Value;
+
+use crate::homeassistant::{split_artists, MediaPlayerMetadata};
+
+/// Integrations that can report their play queue through a service with a
+/// response. HA has no generic queue API, so this is looked up per platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueSource {
+    Sonos,
+    MusicAssistant,
+}
+
+impl QueueSource {
+    pub fn for_platform(platform: &str) -> Option<Self> {
+        match platform {
+            "sonos" => Some(Self::Sonos),
+            "music_assistant" => Some(Self::MusicAssistant),
+            _ => None,
+        }
+    }
+
+    pub fn domain(&self) -> &'static str {
+        match self {
+            Self::Sonos => "sonos",
+            Self::MusicAssistant => "music_assistant",
+        }
+    }
+
+    /// Sonos can jump to and remove any queue position, Music Assistant only
+    /// reports the current and the next item.
+    pub fn can_edit(&self) -> bool {
+        matches!(self, Self::Sonos)
+    }
+
+    /// Reads the `get_queue` response for `entity_id`.
+    pub fn parse(&self, entity_id: &str, result: &Value, current: &MediaPlayerMetadata) -> Queue {
+        let response = &result["response"][entity_id];
+        match self {
+            Self::Sonos => Queue {
+                items: response
+                    .as_array()
+                    .map(Vec::as_slice)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|item| QueueItem {
+                        content_id: text(&item["media_content_id"]),
+                        title: text(&item["media_title"]).unwrap_or_default(),
+                        artists: text(&item["media_artist"])
+                            .map(|a| split_artists(&a))
+                            .unwrap_or_default(),
+                        album: text(&item["media_album_name"]),
+                        duration: 0.0,
+                        art_url: text(&item["media_image"]).unwrap_or_default(),
+                    })
+                    .collect(),
+                current: current.queue_position,
+            },
+            Self::MusicAssistant => {
+                let items: Vec<QueueItem> = ["current_item", "next_item"]
+                    .iter()
+                    .filter(|key| response[**key].is_object())
+                    .map(|key| music_assistant_item(&response[*key]))
+                    .collect();
+                Queue {
+                    current: (response["current_item"].is_object()).then_some(0),
+                    items,
+                }
+            }
+        }
+    }
+}
+
+/// One entry of a player's queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueItem {
+    pub content_id: Option<String>,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub duration: f64,
+    pub art_url: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Queue {
+    pub items: Vec<QueueItem>,
+    /// Index of the playing item, if it is part of the queue.
+    pub current: Option<usize>,
+}
+
+/// How `play_media` adds to the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enqueue {
+    /// Play now, keeping the rest of the queue.
+    Play,
+    /// Right after the current item.
+    Next,
+    /// At the end of the queue.
+    Add,
+}
+
+impl Enqueue {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Play => "play",
+            Self::Next => "next",
+            Self::Add => "add",
+        }
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    value.as_str().filter(|s| !s.is_empty()).map(str::to_string)
+}
+
+fn music_assistant_item(item: &Value) -> QueueItem {
+    let media = &item["media_item"];
+    let artists = media["artists"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|artist| text(&artist["name"]))
+        .collect();
+    QueueItem {
+        content_id: text(&media["uri"]),
+        title: text(&media["name"])
+            .or_else(|| text(&item["name"]))
+            .unwrap_or_default(),
+        artists,
+        album: text(&media["album"]["name"]),
+        duration: item["duration"].as_f64().unwrap_or_default(),
+        art_url: text(&item["image"])
+            .or_else(|| text(&media["image"]))
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::homeassistant::{json_to_metadata, HAState};
+
+    fn playing(queue_position: Option<usize>) -> MediaPlayerMetadata {
+        let attributes = match queue_position {
+            Some(position) => json!({"queue_position": position}),
+            None => json!({}),
+        };
+        json_to_metadata(
+            serde_json::from_value(attributes).unwrap(),
+            HAState::Playing,
+            "http://homeassistant.local:8123".to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn sonos_queues_list_every_track() {
+        let result = json!({"response": {"media_player.kitchen": [
+            {
+                "media_content_id": "x-sonos-spotify:track1",
+                "media_title": "First",
+                "media_artist": "Artist A; Artist B",
+                "media_album_name": "Album",
+                "media_image": "https://i.scdn.co/image/1",
+            },
+            {"media_title": "Second", "media_artist": "", "media_album_name": ""},
+        ]}});
+        let queue = QueueSource::Sonos.parse("media_player.kitchen", &result, &playing(Some(1)));
+
+        assert_eq!(queue.current, Some(1));
+        assert_eq!(
+            queue.items,
+            [
+                QueueItem {
+                    content_id: Some("x-sonos-spotify:track1".to_string()),
+                    title: "First".to_string(),
+                    artists: vec!["Artist A".to_string(), "Artist B".to_string()],
+                    album: Some("Album".to_string()),
+                    duration: 0.0,
+                    art_url: "https://i.scdn.co/image/1".to_string(),
+                },
+                QueueItem {
+                    content_id: None,
+                    title: "Second".to_string(),
+                    artists: vec![],
+                    album: None,
+                    duration: 0.0,
+                    art_url: String::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn music_assistant_queues_list_the_current_and_next_track() {
+        let result = json!({"response": {"media_player.kitchen": {
+            "queue_id": "kitchen",
+            "current_item": {
+                "name": "Artist A - First",
+                "duration": 215,
+                "image": "https://ma.local/image/1",
+                "media_item": {
+                    "uri": "spotify://track/1",
+                    "name": "First",
+                    "artists": [{"name": "Artist A"}, {"name": "Artist B"}],
+                    "album": {"name": "Album"},
+                },
+            },
+            "next_item": {
+                "name": "Artist C - Second",
+                "duration": 180,
+                "media_item": {
+                    "name": "Second",
+                    "image": "https://ma.local/image/2",
+                },
+            },
+        }}});
+        let queue =
+            QueueSource::MusicAssistant.parse("media_player.kitchen", &result, &playing(None));
+
+        assert_eq!(queue.current, Some(0));
+        assert_eq!(
+            queue.items,
+            [
+                QueueItem {
+                    content_id: Some("spotify://track/1".to_string()),
+                    title: "First".to_string(),
+                    artists: vec!["Artist A".to_string(), "Artist B".to_string()],
+                    album: Some("Album".to_string()),
+                    duration: 215.0,
+                    art_url: "https://ma.local/image/1".to_string(),
+                },
+                QueueItem {
+                    content_id: None,
+                    title: "Second".to_string(),
+                    artists: vec![],
+                    album: None,
+                    duration: 180.0,
+                    art_url: "https://ma.local/image/2".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn music_assistant_queues_without_a_current_track_have_no_current_item() {
+        let result = json!({"response": {"media_player.kitchen": {
+            "current_item": null,
+            "next_item": {"name": "Radio", "media_item": {}},
+        }}});
+        let queue =
+            QueueSource::MusicAssistant.parse("media_player.kitchen", &result, &playing(None));
+
+        assert_eq!(queue.current, None);
+        assert_eq!(queue.items.len(), 1);
+        assert_eq!(queue.items[0].title, "Radio");
+    }
+
+    #[test]
+    fn missing_responses_are_empty_queues() {
+        let result = json!({"response": {}});
+        for source in [QueueSource::Sonos, QueueSource::MusicAssistant] {
+            let queue = source.parse("media_player.kitchen", &result, &playing(None));
+            assert_eq!(queue, Queue::default());
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::{path::Path, time::Duration};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+/// Editors save in several steps, so changes within this time make one reload.
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// Notifies `reload` whenever the config file changes or the process gets
+/// SIGHUP.
+pub fn watch_config(config_file: &Path, reload: mpsc::Sender<()>) {
+    let (changed_tx, mut changed_rx) = mpsc::channel(1);
+    let watcher = watch_file(config_file, changed_tx)
+        .map_err(|e| {
+            eprintln!(
+                "Not watching {} for changes, only reloading on SIGHUP: {e}",
+                config_file.display()
+            )
+        })
+        .ok();
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| eprintln!("Not reloading the config on SIGHUP: {e}"))
+        .ok();
+
+    tokio::spawn(async move {
+        // Dropping the watcher stops it
+        let _watcher = watcher;
+        loop {
+            tokio::select! {
+                Some(()) = changed_rx.recv() => {
+                    tokio::time::sleep(SETTLE_TIME).await;
+                    while changed_rx.try_recv().is_ok() {}
+                }
+                Some(()) = async {
+                    match &mut hangup {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => println!("Reloading the config after SIGHUP"),
+                else => break,
+            }
+            if reload.send(()).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Watches the directory rather than the file, as editors tend to replace
+/// the file instead of writing to it.
+fn watch_file(config_file: &Path, changed: mpsc::Sender<()>) -> notify::Result<RecommendedWatcher> {
+    let name = config_file.file_name().map(ToOwned::to_owned);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else { return };
+        // Reading the file to reload it must not trigger another reload
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) {
+            return;
+        }
+        if event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == name.as_deref())
+        {
+            // A full channel already means a reload is coming
+            let _ = changed.try_send(());
+        }
+    })?;
+    let dir = config_file
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}